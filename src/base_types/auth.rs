@@ -1,5 +1,12 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+};
+
 use rand::{prelude::*, distributions::Alphanumeric};
 
+use super::snowflake::Snowflake;
+
 pub type BaseToken = [u8; 16];
 
 fn gen_token() -> BaseToken {
@@ -13,11 +20,88 @@ fn gen_token() -> BaseToken {
 }
 
 /// A session Id, where the Id is an 8 byte snowflake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SessionId(pub u64);
 
+impl SessionId {
+    /// Mint a new session ID from `sf`. IDs from the same generator increase monotonically.
+    pub fn generate(sf: &mut Snowflake) -> Self {
+        SessionId(sf.generate() as u64)
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    pub fn from_le_bytes(bytes: [u8; 8]) -> Self {
+        SessionId(u64::from_le_bytes(bytes))
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// A token for a user. This data is 16 bytes of noise, as generated by [rand](https://crates.io/crates/rand). 
 pub struct Token(pub BaseToken);
 
+/// The 16 byte token a client logs in with, as carried by a `Login` packet.
+///
+/// Comparison runs in constant time, so token lookups don't leak how many leading bytes matched,
+/// and `Debug` redacts the bytes so the secret doesn't end up in logs.
+#[derive(Clone, Copy)]
+pub struct UserToken(BaseToken);
+
+impl UserToken {
+    pub fn from_bytes(bytes: BaseToken) -> Self {
+        UserToken(bytes)
+    }
+
+    /// Parse 32 hex digits, or `None` if `hex` is anything else.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        // `from_str_radix` also accepts a leading `+`, so check every digit up front.
+        if hex.len() != 32 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let mut bytes = [0; 16];
+        for (byte, i) in bytes.iter_mut().zip((0..32).step_by(2)) {
+            *byte = u8::from_str_radix(&hex[i..i + 2], 16).ok()?;
+        }
+        Some(UserToken(bytes))
+    }
+
+    /// Format the token as 32 lowercase hex digits.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn as_bytes(&self) -> &BaseToken {
+        &self.0
+    }
+}
+
+impl fmt::Debug for UserToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UserToken(..)")
+    }
+}
+
+impl PartialEq for UserToken {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.iter().zip(other.0.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+impl Eq for UserToken {}
+
+impl Hash for UserToken {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -26,4 +110,30 @@ mod test {
     fn id() {
         println!("{:?}", gen_token())
     }
+
+    #[test]
+    fn session_id() {
+        let mut sf = Snowflake::default();
+        let first = SessionId::generate(&mut sf);
+        let second = SessionId::generate(&mut sf);
+        assert!(second.0 > first.0);
+
+        assert_eq!(SessionId::from_le_bytes(first.to_le_bytes()), first);
+        assert_eq!(SessionId(42).to_string(), "42");
+    }
+
+    #[test]
+    fn user_token() {
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        assert_eq!(token, UserToken::from_bytes(*b"0123456789abcdef"));
+        assert_ne!(token, UserToken::from_bytes(*b"0123456789abcdeg"));
+
+        assert_eq!(UserToken::from_hex(&token.to_hex()), Some(token));
+        assert_eq!(token.to_hex(), "30313233343536373839616263646566");
+        assert_eq!(UserToken::from_hex("3031"), None);
+        assert_eq!(UserToken::from_hex(&"zz".repeat(16)), None);
+        assert_eq!(UserToken::from_hex(&"+1".repeat(16)), None);
+
+        assert_eq!(format!("{:?}", token), "UserToken(..)");
+    }
 }
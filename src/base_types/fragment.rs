@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::{
+    auth::SessionId,
+    packet::{content_offset, split_content, Flag, Opcode, Packet, PacketError, CONTENT_LEN},
+};
+
+/// Split `content` across as many `Message` packets as it needs, see [`Packet::wire_frame_count`].
+///
+/// Every fragment shares `message_id` and carries its index and the fragment count. All but the
+/// last have [`Flag::Continuation`] set and record how much of their content is padding, so NULs
+/// at the end of a chunk survive reassembly. Fails if the content needs more than 255 fragments.
+pub fn fragment(
+    user_id: u64,
+    message_id: u64,
+    session_id: SessionId,
+    content: &str,
+) -> Result<Vec<Packet>, PacketError> {
+    let chunks = split_content(content);
+    let count: u8 = chunks
+        .len()
+        .try_into()
+        .map_err(|_| PacketError::BadContent { t: Opcode::Message, offset: None })?;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut packet = Packet::message(user_id, message_id, session_id, chunk)?;
+            packet.set_fragment_index(index as u8)?;
+            packet.set_fragment_count(count)?;
+            if index + 1 < count as usize {
+                packet.set_flag(Flag::Continuation, true);
+                packet.set_fragment_padding((CONTENT_LEN - chunk.len()) as u8)?;
+            }
+            Ok(packet)
+        })
+        .collect()
+}
+
+/// Rebuilds messages split by [`fragment`], whatever order their fragments arrive in.
+pub struct MessageReassembler {
+    timeout: Duration,
+    partial: HashMap<u64, Partial>,
+}
+
+/// The fragments of one message received so far.
+struct Partial {
+    fragments: Vec<Option<String>>,
+    started: Instant,
+}
+
+impl MessageReassembler {
+    /// Give up on a message if it is still incomplete `timeout` after its first fragment arrived.
+    pub fn new(timeout: Duration) -> Self {
+        MessageReassembler {
+            timeout,
+            partial: HashMap::new(),
+        }
+    }
+
+    /// Add a `Message`, and get the whole content once every fragment of its message has arrived.
+    /// A message that was never fragmented is returned straight away.
+    ///
+    /// Fails for any other opcode, and for a fragment whose index or count doesn't fit the
+    /// fragments already received.
+    pub fn push(&mut self, packet: &Packet) -> Result<Option<String>, PacketError> {
+        self.push_at(packet, Instant::now())
+    }
+
+    /// Forget messages that timed out, returning their message IDs.
+    pub fn expire(&mut self) -> Vec<u64> {
+        self.expire_at(Instant::now())
+    }
+
+    fn push_at(&mut self, packet: &Packet, now: Instant) -> Result<Option<String>, PacketError> {
+        let content = packet.message_content()?;
+        let (message_id, index, count) = match (
+            packet.message_id(),
+            packet.fragment_index(),
+            packet.fragment_count(),
+        ) {
+            (Some(message_id), Some(index), Some(count)) => (message_id, index, count),
+            _ => return Err(PacketError::InvaidContent { t: packet.op() }),
+        };
+        if count == 0 {
+            return Ok(Some(content));
+        }
+        if index >= count {
+            return Err(PacketError::BadContent { t: Opcode::Message, offset: Some(11) });
+        }
+        let content = if index + 1 < count { chunk(packet)? } else { content };
+
+        self.expire_at(now);
+        let partial = self.partial.entry(message_id).or_insert_with(|| Partial {
+            fragments: vec![None; count as usize],
+            started: now,
+        });
+        if partial.fragments.len() != count as usize {
+            return Err(PacketError::BadContent { t: Opcode::Message, offset: Some(20) });
+        }
+        partial.fragments[index as usize] = Some(content);
+
+        if partial.fragments.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&message_id).unwrap();
+        Ok(Some(partial.fragments.into_iter().flatten().collect()))
+    }
+
+    fn expire_at(&mut self, now: Instant) -> Vec<u64> {
+        let timeout = self.timeout;
+        let expired: Vec<u64> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now.saturating_duration_since(partial.started) > timeout)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in &expired {
+            self.partial.remove(id);
+        }
+        expired
+    }
+}
+
+/// Get the text of a fragment other than the last, cutting off only the padding it records.
+/// Trimming trailing NULs instead would drop any that end the chunk.
+fn chunk(packet: &Packet) -> Result<String, PacketError> {
+    let padding = packet.fragment_padding().unwrap_or(0) as usize;
+    let content = packet.content().unwrap();
+    std::str::from_utf8(&content.0[..CONTENT_LEN - padding])
+        .map(str::to_owned)
+        .map_err(|e| PacketError::BadContent {
+            t: Opcode::Message,
+            offset: Some(content_offset(Opcode::Message) + e.valid_up_to()),
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::seq::SliceRandom;
+
+    #[test]
+    fn roundtrip() {
+        let content = "héllo wörld ".repeat(400);
+        assert!(content.len() > 5000);
+
+        let mut packets = fragment(1, 2, SessionId(3), &content).unwrap();
+        assert_eq!(packets.len(), Packet::wire_frame_count(&content));
+        assert!(packets[..packets.len() - 1].iter().all(|p| p.has_flag(Flag::Continuation)));
+        assert!(!packets.last().unwrap().has_flag(Flag::Continuation));
+
+        packets.shuffle(&mut rand::thread_rng());
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(30));
+        let (last, rest) = packets.split_last().unwrap();
+        for packet in rest {
+            assert_eq!(reassembler.push(packet).unwrap(), None);
+        }
+        assert_eq!(reassembler.push(last).unwrap(), Some(content));
+    }
+
+    #[test]
+    fn nul_at_fragment_boundary() {
+        // The first chunk ends in a NUL, and the second in a NUL followed by one byte of padding.
+        let content = format!("{}\0{}\0€c", "a".repeat(989), "b".repeat(CONTENT_LEN - 2));
+        let packets = fragment(1, 2, SessionId(3), &content).unwrap();
+        assert_eq!(packets.len(), 3);
+        assert_eq!(packets[0].fragment_padding(), Some(0));
+        assert_eq!(packets[1].fragment_padding(), Some(1));
+
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(30));
+        assert_eq!(reassembler.push(&packets[0]).unwrap(), None);
+        assert_eq!(reassembler.push(&packets[1]).unwrap(), None);
+        assert_eq!(reassembler.push(&packets[2]).unwrap(), Some(content));
+    }
+
+    #[test]
+    fn unfragmented() {
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(30));
+        let message = Packet::message(1, 2, SessionId(3), "short").unwrap();
+        assert_eq!(reassembler.push(&message).unwrap().as_deref(), Some("short"));
+        assert!(reassembler.push(&Packet::ping(1, 0)).is_err());
+        assert!(fragment(1, 2, SessionId(3), &"a".repeat(CONTENT_LEN * 256)).is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        let packets = fragment(1, 2, SessionId(3), &"a".repeat(3000)).unwrap();
+        let mut reassembler = MessageReassembler::new(Duration::from_secs(30));
+        let start = Instant::now();
+        assert_eq!(reassembler.push_at(&packets[0], start).unwrap(), None);
+
+        let later = start + Duration::from_secs(31);
+        assert_eq!(reassembler.expire_at(later), vec![2]);
+        for packet in &packets[1..] {
+            assert_eq!(reassembler.push_at(packet, later).unwrap(), None);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use super::{
+    auth::SessionId,
+    packet::{PacketMessageContent, CONTENT_LEN},
+};
+
+/// A value stored at a fixed offset in a packet.
+pub(crate) trait LayoutField: Sized {
+    /// How many bytes the field takes up.
+    const SIZE: usize;
+
+    /// Read the field from exactly `SIZE` bytes.
+    fn read(bytes: &[u8]) -> Self;
+
+    /// Write the field into exactly `SIZE` bytes.
+    fn write(&self, bytes: &mut [u8]);
+}
+
+impl LayoutField for u8 {
+    const SIZE: usize = 1;
+
+    fn read(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[0] = *self;
+    }
+}
+
+impl LayoutField for u64 {
+    const SIZE: usize = 8;
+
+    fn read(bytes: &[u8]) -> Self {
+        u64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl LayoutField for i64 {
+    const SIZE: usize = 8;
+
+    fn read(bytes: &[u8]) -> Self {
+        i64::from_le_bytes(bytes.try_into().unwrap())
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl LayoutField for SessionId {
+    const SIZE: usize = 8;
+
+    fn read(bytes: &[u8]) -> Self {
+        SessionId(u64::read(bytes))
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        self.0.write(bytes)
+    }
+}
+
+impl LayoutField for PacketMessageContent {
+    const SIZE: usize = CONTENT_LEN;
+
+    fn read(bytes: &[u8]) -> Self {
+        PacketMessageContent(bytes.try_into().unwrap())
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(&self.0);
+    }
+}
+
+/// Generate accessors on `Packet` for fields shared by one or more opcodes' layouts.
+///
+/// Each field is declared as `getter, setter: Type = offset;`, or `getter: Type = offset;` when
+/// `Packet` has a hand-written setter for it. Getters return `None` and setters fail with
+/// `InvaidContent` on packets of any other opcode. A field that would run into the checksum fails
+/// to compile.
+macro_rules! packet_layout {
+    ($($op:ident)|+ { $($fields:tt)* }) => {
+        packet_layout!(@fields [$($op)|+] $($fields)*);
+    };
+
+    (@fields $ops:tt $get:ident, $set:ident: $ty:ty = $offset:expr; $($rest:tt)*) => {
+        packet_layout!(@get $ops $get, $ty, $offset);
+        packet_layout!(@set $ops $get, $set, $ty, $offset);
+        packet_layout!(@fields $ops $($rest)*);
+    };
+    (@fields $ops:tt $get:ident: $ty:ty = $offset:expr; $($rest:tt)*) => {
+        packet_layout!(@get $ops $get, $ty, $offset);
+        packet_layout!(@fields $ops $($rest)*);
+    };
+    (@fields $ops:tt) => {};
+
+    (@get [$($op:ident)|+] $get:ident, $ty:ty, $offset:expr) => {
+        const _: () = assert!(
+            $offset >= 3
+                && $offset + <$ty as $crate::base_types::layout::LayoutField>::SIZE
+                    <= $crate::base_types::packet::CHECKSUM_OFFSET
+        );
+
+        impl $crate::base_types::packet::Packet {
+            #[doc = concat!("Get the `", stringify!($get), "` of a ", $("`", stringify!($op), "` ",)+ "packet, or `None` for any other opcode.")]
+            pub fn $get(&self) -> Option<$ty> {
+                if !matches!(self.try_op(), Ok($($crate::base_types::packet::Opcode::$op)|+)) {
+                    return None;
+                }
+                let size = <$ty as $crate::base_types::layout::LayoutField>::SIZE;
+                Some(<$ty as $crate::base_types::layout::LayoutField>::read(&self.0[$offset..$offset + size]))
+            }
+        }
+    };
+
+    (@set [$($op:ident)|+] $get:ident, $set:ident, $ty:ty, $offset:expr) => {
+        impl $crate::base_types::packet::Packet {
+            #[doc = concat!("Set the `", stringify!($get), "` of a ", $("`", stringify!($op), "` ",)+ "packet.")]
+            pub fn $set(&mut self, value: $ty) -> Result<(), $crate::base_types::packet::PacketError> {
+                let op = self.try_op()?;
+                if !matches!(op, $($crate::base_types::packet::Opcode::$op)|+) {
+                    return Err($crate::base_types::packet::PacketError::InvaidContent { t: op });
+                }
+                let size = <$ty as $crate::base_types::layout::LayoutField>::SIZE;
+                $crate::base_types::layout::LayoutField::write(&value, &mut self.0[$offset..$offset + size]);
+                Ok(())
+            }
+        }
+    };
+}
+
+// IDs read as `u64` and session IDs as `SessionId`, the types `Packet::message` and the server
+// already take. Snowflakes never set the sign bit, so every ID has the same value as an `i64`.
+packet_layout!(Ping | MemberJoin | MemberLeave | Message {
+    user_id, set_user_id: u64 = 3;
+});
+
+packet_layout!(Ping | MemberJoin | MemberLeave {
+    timestamp, set_timestamp: i64 = 12;
+});
+
+packet_layout!(Message {
+    message_id, set_message_id: u64 = 12;
+    session_id, set_session_id: SessionId = 21;
+    content: PacketMessageContent = 30;
+    fragment_index, set_fragment_index: u8 = 11;
+    fragment_count, set_fragment_count: u8 = 20;
+    fragment_padding, set_fragment_padding: u8 = 29;
+});
+
+#[cfg(test)]
+mod test {
+    use crate::base_types::{
+        auth::SessionId,
+        packet::{Opcode, Packet, PacketError},
+    };
+
+    #[test]
+    fn message_layout() {
+        let mut packet = Packet::new([0; 1024]);
+        packet.reset(Opcode::Message);
+        packet.set_snowflake(1u64.to_le_bytes(), 3);
+        packet.set_snowflake(2u64.to_le_bytes(), 12);
+        packet.set_snowflake(3u64.to_le_bytes(), 21);
+        packet.0[30..33].copy_from_slice(b"uwu");
+
+        assert_eq!(packet.user_id(), Some(1));
+        assert_eq!(packet.message_id(), Some(2));
+        assert_eq!(packet.session_id(), Some(SessionId(3)));
+        assert_eq!(packet.content().unwrap().content_str().unwrap(), "uwu");
+
+        packet.set_user_id(4).unwrap();
+        packet.set_message_id(5).unwrap();
+        packet.set_session_id(SessionId(6)).unwrap();
+        packet.set_content("owo".parse().unwrap()).unwrap();
+        assert_eq!(u64::from_le_bytes(packet.snowflake(3)), 4);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(12)), 5);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(21)), 6);
+        assert_eq!(&packet.0[30..33], b"owo");
+
+        let mut ping = Packet::ping_with_nonce(1, 2);
+        assert_eq!(ping.message_id(), None);
+        assert_eq!(ping.session_id(), None);
+        assert!(matches!(
+            ping.set_session_id(SessionId(1)),
+            Err(PacketError::InvaidContent { t: Opcode::Ping })
+        ));
+    }
+
+    #[test]
+    fn shared_fields() {
+        assert_eq!(Packet::ping(7, 0).user_id(), Some(7));
+        assert_eq!(Packet::member_join(8, 0).user_id(), Some(8));
+        assert_eq!(Packet::member_leave(9, 0).user_id(), Some(9));
+        assert_eq!(Packet::login_ok(SessionId(1)).user_id(), None);
+
+        let mut ping = Packet::ping(7, 0);
+        ping.set_timestamp(1_600_000_000_000).unwrap();
+        assert_eq!(ping.timestamp(), Some(1_600_000_000_000));
+        assert_eq!(ping.snowflake(12), 1_600_000_000_000i64.to_le_bytes());
+        assert_eq!(Packet::member_leave(9, 5).timestamp(), Some(5));
+        assert_eq!(Packet::message(1, 2, SessionId(3), "").unwrap().timestamp(), None);
+        assert!(Packet::login_ok(SessionId(1)).set_timestamp(5).is_err());
+
+        let mut join = Packet::member_join(8, 0);
+        join.set_user_id(10).unwrap();
+        assert_eq!(join.user_id(), Some(10));
+    }
+}
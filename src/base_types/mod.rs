@@ -1,3 +1,10 @@
 pub mod auth;
+pub mod fragment;
+mod layout;
 pub mod packet;
-pub mod snowflake;
\ No newline at end of file
+pub mod snowflake;
+pub mod stream;
+pub mod wire;
+
+#[cfg(feature = "serde")]
+mod serde_impl;
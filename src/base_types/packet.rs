@@ -1,14 +1,81 @@
-use super::snowflake::Snowflake;
+use super::{
+    auth::{SessionId, UserToken},
+    snowflake::Snowflake,
+};
+use chrono::Utc;
 use lazy_static::lazy_static;
 use parking_lot::{lock_api::Mutex, RawMutex};
-use std::{error::Error, fmt::Display, str::FromStr};
+use std::{
+    error::Error,
+    fmt::{self, Display, Write},
+    ops::Range,
+    str::FromStr,
+};
 
 pub type BasePacket = [u8; 1024];
 
-pub struct PacketMessageContent(pub [u8; 994]);
+pub struct PacketMessageContent(pub [u8; CONTENT_LEN]);
+
+/// What to strip from the end of message content when decoding it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Trim {
+    /// Strip the trailing NUL padding.
+    #[default]
+    TrailingNul,
+    /// Strip trailing NULs and ASCII whitespace, in any mix.
+    TrailingWhitespaceAndNul,
+    /// Keep all 990 bytes.
+    None,
+}
 
 pub const PROTOCOL_VERSION: u8 = 1;
 
+/// Where the content area of a `Message` starts.
+const CONTENT_OFFSET: usize = content_offset(Opcode::Message);
+
+/// Where the checksum starts. The last 4 bytes of every packet are a CRC32 of the bytes before.
+pub const CHECKSUM_OFFSET: usize = 1020;
+
+/// How many bytes of content a `Message` holds.
+pub const CONTENT_LEN: usize = content_len(Opcode::Message);
+
+/// Get where the string content of `op` starts. Content always runs up to the checksum, so opcodes
+/// without any start there.
+pub const fn content_offset(op: Opcode) -> usize {
+    match op {
+        Opcode::Message => 30,
+        Opcode::Notice => 4,
+        Opcode::Reaction => 11,
+        _ => CHECKSUM_OFFSET,
+    }
+}
+
+/// Get how many bytes of string content `op` holds, or 0 if it carries none.
+pub const fn content_len(op: Opcode) -> usize {
+    CHECKSUM_OFFSET - content_offset(op)
+}
+
+/// Cut the trailing NUL padding off `bytes`. NULs before the last other byte are kept.
+pub(crate) fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &bytes[..end]
+}
+
+/// Split `content` into the chunks [`Packet::wire_frame_count`] counts, each fitting one `Message`
+/// without splitting a character. Empty content is one empty chunk.
+pub(crate) fn split_content(content: &str) -> Vec<&str> {
+    let mut chunks = vec![];
+    let mut start = 0;
+    for (i, c) in content.char_indices() {
+        if i + c.len_utf8() - start > CONTENT_LEN {
+            chunks.push(&content[start..i]);
+            start = i;
+        }
+    }
+    chunks.push(&content[start..]);
+    chunks
+}
+
 lazy_static! {
     pub static ref SNOWFLAKE: Mutex<RawMutex, Snowflake> = Mutex::new(Default::default());
 }
@@ -18,16 +85,398 @@ lazy_static! {
 /// `1` - Protocol version
 ///
 /// `2` - Opcode
+///
+/// `3` - Flags, see [`Flag`]
+///
+/// `1021-1024` - CRC32 of bytes `1-1020`, see [`Packet::set_checksum`]. Message content and
+/// other payloads stop short of it.
 pub struct Packet(pub BasePacket);
 
+/// Bits of a packet's flags byte.
+///
+/// `0x01` - Compressed
+///
+/// `0x02` - Binary
+///
+/// `0x04` - Continuation
+///
+/// The remaining bits are reserved and must be zero.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    /// The content is compressed.
+    Compressed = 0x01,
+    /// The content is raw bytes rather than UTF-8 text.
+    Binary = 0x02,
+    /// The content continues in the next packet.
+    Continuation = 0x04,
+}
+
 impl Packet {
     pub fn new(inner: BasePacket) -> Self {
         Packet(inner)
     }
 
+    /// A zeroed packet of `op` with the current protocol version.
+    fn blank(op: Opcode) -> Self {
+        let mut packet = Packet::new([0; 1024]);
+        packet.reset(op);
+        packet
+    }
+
+    /// Prepare a possibly reused buffer for a packet of `op`. Sets the version and opcode, clears
+    /// the flags and zeroes every byte outside the opcode's payload range, so no stale data from
+    /// the previous packet ends up on the wire.
+    pub fn reset(&mut self, op: Opcode) {
+        self.set_version(PROTOCOL_VERSION);
+        self.set_op(op);
+        self.0[2] = 0;
+        self.zero_unused_payload(op);
+    }
+
+    /// Zero everything after the header that is outside `op`'s payload range.
+    fn zero_unused_payload(&mut self, op: Opcode) {
+        let range = op.payload_range();
+        self.0[3..range.start].fill(0);
+        self.0[range.end..].fill(0);
+    }
+
+    /// Convert a `Message` in the legacy layout into the current one.
+    ///
+    /// The legacy layout has no session ID and stores 1003 bytes of content at `20-1023`. The
+    /// session ID of the result is zero, and content past the first 990 bytes is dropped, backing
+    /// off to a character boundary. Only the user and message IDs are carried over, so the result
+    /// has the current protocol version, no flags and no fragment fields, whatever the legacy
+    /// packet held there. Packets with any other opcode are returned unchanged.
+    pub fn migrate_legacy(old: BasePacket) -> Self {
+        if old[1] != Opcode::Message as u8 {
+            return Packet::new(old);
+        }
+
+        let mut content = trim_nul(&old[20..1023]);
+        if content.len() > CONTENT_LEN {
+            let end = (0..=CONTENT_LEN).rev().find(|&i| content[i] & 0xc0 != 0x80).unwrap_or(0);
+            content = &content[..end];
+        }
+
+        let mut packet = Packet::blank(Opcode::Message);
+        packet.0[3..11].copy_from_slice(&old[3..11]);
+        packet.0[12..20].copy_from_slice(&old[12..20]);
+        packet.0[CONTENT_OFFSET..CONTENT_OFFSET + content.len()].copy_from_slice(content);
+        packet
+    }
+
+    /// Build a `Message`. Fails if `content` is longer than 990 bytes.
+    pub fn message(
+        user_id: u64,
+        message_id: u64,
+        session_id: SessionId,
+        content: &str,
+    ) -> Result<Self, PacketError> {
+        let content = content.parse()?;
+        let mut packet = Packet::blank(Opcode::Message);
+        packet.set_message_ids(user_id, message_id, session_id.0)?;
+        packet.set_content(content)?;
+        Ok(packet)
+    }
+
+    /// Build a `Ping` stamped with `timestamp` in Unix milliseconds.
+    pub fn ping(user_id: u64, timestamp: i64) -> Self {
+        Packet::user_stamped(Opcode::Ping, user_id, timestamp)
+    }
+
+    /// Build a `MemberJoin` stamped with `timestamp` in Unix milliseconds.
+    pub fn member_join(user_id: u64, timestamp: i64) -> Self {
+        Packet::user_stamped(Opcode::MemberJoin, user_id, timestamp)
+    }
+
+    /// Build a `MemberLeave` stamped with `timestamp` in Unix milliseconds.
+    pub fn member_leave(user_id: u64, timestamp: i64) -> Self {
+        Packet::user_stamped(Opcode::MemberLeave, user_id, timestamp)
+    }
+
+    /// A packet of `op` with a user ID at `3-11` and a timestamp at `12-20`.
+    fn user_stamped(op: Opcode, user_id: u64, timestamp: i64) -> Self {
+        let mut packet = Packet::blank(op);
+        packet.set_snowflake(user_id.to_le_bytes(), 3);
+        packet.set_snowflake(timestamp.to_le_bytes(), 12);
+        packet
+    }
+
+    /// Build a `Login` carrying the user's token.
+    pub fn login(token: UserToken) -> Self {
+        let mut packet = Packet::blank(Opcode::Login);
+        packet.0[3..19].copy_from_slice(token.as_bytes());
+        packet
+    }
+
+    /// Get the token of a `Login` packet, or `None` for any other opcode.
+    pub fn login_token(&self) -> Option<UserToken> {
+        if self.0[1] != Opcode::Login as u8 {
+            return None;
+        }
+        Some(UserToken::from_bytes(self.0[3..19].try_into().unwrap()))
+    }
+
+    /// Build a `LoginOk` reply carrying the session assigned to the client.
+    pub fn login_ok(session: SessionId) -> Self {
+        let mut packet = Packet::blank(Opcode::LoginOk);
+        packet.set_snowflake(session.to_le_bytes(), 3);
+        packet
+    }
+
+    /// Build a `Ping` carrying a caller-chosen nonce, stamped with the current time.
+    pub fn ping_with_nonce(user_id: u64, nonce: u64) -> Self {
+        let mut packet = Packet::ping(user_id, Utc::now().timestamp_millis());
+        packet.set_snowflake(nonce.to_le_bytes(), 21);
+        packet
+    }
+
+    /// Build the `Ok` reply to a `Ping`, echoing its nonce.
+    pub fn ping_reply(ping: &Packet) -> Self {
+        let mut packet = Packet::blank(Opcode::Ok);
+        packet.set_snowflake(ping.snowflake(21), 21);
+        packet
+    }
+
+    /// Get the nonce of a `Ping`, or the echoed nonce of an `Ok`. `None` for any other opcode.
+    pub fn ping_nonce(&self) -> Option<u64> {
+        if self.0[1] != Opcode::Ping as u8 && self.0[1] != Opcode::Ok as u8 {
+            return None;
+        }
+        Some(self.snowflake_for_version(21))
+    }
+
+    /// Build an `Error` packet with the given code.
+    pub fn error(code: ErrorCode) -> Self {
+        let mut packet = Packet::blank(Opcode::Error);
+        packet.0[3] = code as u8;
+        packet
+    }
+
+    /// Get the code of an `Error` packet, or `None` for any other opcode or an unknown code.
+    pub fn error_code(&self) -> Option<ErrorCode> {
+        if self.0[1] != Opcode::Error as u8 {
+            return None;
+        }
+        ErrorCode::from_u8(self.0[3])
+    }
+
+    /// Build a `Notice`. Fails if `text` is longer than 1016 bytes.
+    pub fn notice(severity: Severity, text: &str) -> Result<Self, PacketError> {
+        let text = text.as_bytes();
+        if text.len() > content_len(Opcode::Notice) {
+            return Err(PacketError::BadContent { t: Opcode::Notice, offset: None });
+        }
+
+        let start = content_offset(Opcode::Notice);
+        let mut packet = Packet::blank(Opcode::Notice);
+        packet.0[3] = severity as u8;
+        packet.0[start..start + text.len()].copy_from_slice(text);
+        Ok(packet)
+    }
+
+    /// Get the severity of a `Notice`, or `None` for any other opcode or an unknown severity.
+    pub fn notice_severity(&self) -> Option<Severity> {
+        if self.0[1] != Opcode::Notice as u8 {
+            return None;
+        }
+        Severity::from_u8(self.0[3])
+    }
+
+    /// Get the text of a `Notice`, without its trailing NUL padding.
+    pub fn notice_text(&self) -> Result<&str, PacketError> {
+        match self.try_op()? {
+            Opcode::Notice => self.content_str(Opcode::Notice),
+            t => Err(PacketError::InvaidContent { t }),
+        }
+    }
+
+    /// Build a `Reaction` to the message `message_id`. Fails if `emoji` is longer than 1009 bytes.
+    pub fn reaction(message_id: u64, emoji: &str) -> Result<Self, PacketError> {
+        let emoji = emoji.as_bytes();
+        if emoji.len() > content_len(Opcode::Reaction) {
+            return Err(PacketError::BadContent { t: Opcode::Reaction, offset: None });
+        }
+
+        let start = content_offset(Opcode::Reaction);
+        let mut packet = Packet::blank(Opcode::Reaction);
+        packet.0[3..11].copy_from_slice(&message_id.to_le_bytes());
+        packet.0[start..start + emoji.len()].copy_from_slice(emoji);
+        Ok(packet)
+    }
+
+    /// Get the ID of the message a `Reaction` targets, or `None` for any other opcode.
+    pub fn reaction_target(&self) -> Option<u64> {
+        if self.0[1] != Opcode::Reaction as u8 {
+            return None;
+        }
+        Some(self.snowflake_for_version(3))
+    }
+
+    /// Get the emoji of a `Reaction`, without its trailing NUL padding.
+    pub fn reaction_emoji(&self) -> Result<&str, PacketError> {
+        match self.try_op()? {
+            Opcode::Reaction => self.content_str(Opcode::Reaction),
+            t => Err(PacketError::InvaidContent { t }),
+        }
+    }
+
+    /// Get the text `op` stores from its content offset up to the checksum, without the trailing
+    /// NUL padding. The opcode is not checked.
+    fn content_str(&self, op: Opcode) -> Result<&str, PacketError> {
+        let start = content_offset(op);
+        std::str::from_utf8(trim_nul(&self.0[start..CHECKSUM_OFFSET])).map_err(|e| {
+            PacketError::BadContent { t: op, offset: Some(start + e.valid_up_to()) }
+        })
+    }
+
+    /// Set the user, message and session IDs of a `Message` at once. Fails for any other opcode.
+    pub fn set_message_ids(&mut self, user: u64, message: u64, session: u64) -> Result<(), PacketError> {
+        // Checked up front, since `user_id` is shared with other opcodes and would otherwise be
+        // written before the failure.
+        match self.try_op()? {
+            Opcode::Message => {}
+            t => return Err(PacketError::InvaidContent { t }),
+        }
+        self.set_user_id(user)?;
+        self.set_message_id(message)?;
+        self.set_session_id(SessionId(session))
+    }
+
+    /// Get the session ID of a `LoginOk` packet, or `None` for any other opcode.
+    pub fn login_ok_session(&self) -> Option<SessionId> {
+        if self.0[1] != Opcode::LoginOk as u8 {
+            return None;
+        }
+        Some(SessionId(self.snowflake_for_version(3)))
+    }
+
+    /// Compare the header and the opcode's payload range, ignoring padding. Packets with an unknown
+    /// opcode have no known padding, so they are compared byte for byte.
+    pub fn semantic_eq(&self, other: &Packet) -> bool {
+        if self.0[..3] != other.0[..3] {
+            return false;
+        }
+        match self.try_op() {
+            Ok(op) => self.0[op.payload_range()] == other.0[op.payload_range()],
+            Err(_) => self.0 == other.0,
+        }
+    }
+
+    /// Hash the opcode and the meaningful content with 64-bit FNV-1a, which is stable across builds
+    /// and platforms.
+    ///
+    /// For a `Message` only the content is hashed, without its trailing NUL padding, so messages
+    /// with the same text but different IDs hash equal. Other opcodes hash their payload range, and
+    /// an unknown opcode everything after the header.
+    pub fn content_hash(&self) -> u64 {
+        let content = match self.try_op() {
+            Ok(Opcode::Message) => trim_nul(&self.0[CONTENT_OFFSET..CHECKSUM_OFFSET]),
+            Ok(op) => &self.0[op.payload_range()],
+            Err(_) => &self.0[3..],
+        };
+
+        std::iter::once(&self.0[1])
+            .chain(content)
+            .fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+                (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+            })
+    }
+
+    /// Get the content of a `Message` as a `String`, without its trailing NUL padding. NULs inside
+    /// the text are kept.
+    pub fn message_content(&self) -> Result<String, PacketError> {
+        match self.try_op()? {
+            Opcode::Message => self.content_str(Opcode::Message).map(str::to_owned),
+            t => Err(PacketError::InvaidContent { t }),
+        }
+    }
+
+    /// Get how many frames `content` takes up when split across `Message` packets chained with
+    /// [`Flag::Continuation`]. Each frame holds up to 990 bytes and a character is never split
+    /// between frames. Empty content still takes one frame.
+    pub fn wire_frame_count(content: &str) -> usize {
+        split_content(content).len()
+    }
+
+    /// Get a single-line preview of a `Message`'s content for notification banners.
+    ///
+    /// Control characters are stripped, runs of whitespace including newlines collapse to one
+    /// space, invalid UTF-8 is replaced, and anything past `max_chars` characters is cut off with
+    /// an ellipsis, which counts toward the limit. Empty for any other opcode.
+    ///
+    /// The limit counts `char`s, not graphemes, since the crate has no grapheme segmentation. A
+    /// cluster such as an emoji with a skin tone modifier counts as several characters and can be
+    /// split by the cut.
+    pub fn content_preview(&self, max_chars: usize) -> String {
+        let content = match self.content() {
+            Some(content) => content,
+            None => return String::new(),
+        };
+        let text: String = String::from_utf8_lossy(trim_nul(&content.0))
+            .chars()
+            .filter(|c| !c.is_control() || c.is_whitespace())
+            .collect();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        if text.chars().count() <= max_chars {
+            return text;
+        }
+        if max_chars == 0 {
+            return String::new();
+        }
+        let mut preview: String = text.chars().take(max_chars - 1).collect();
+        preview.truncate(preview.trim_end().len());
+        preview.push('…');
+        preview
+    }
+
+    /// Check that the packet has a compatible protocol version and a known opcode.
+    ///
+    /// Version 0 is never valid, so a frame with it, such as a buffer that was never filled in,
+    /// fails with `Uninitialized`. Versions newer than [`PROTOCOL_VERSION`] fail with
+    /// `VersionMismatch`.
+    pub fn validate(&self) -> Result<(), PacketError> {
+        match self.0[0] {
+            0 => return Err(PacketError::Uninitialized),
+            version if version > PROTOCOL_VERSION => {
+                return Err(PacketError::VersionMismatch { got: version, supported: PROTOCOL_VERSION })
+            }
+            _ => {}
+        }
+        self.try_op()?;
+        Ok(())
+    }
+
+    /// Check whether this crate can read the packet's version: any from 1 up to and including
+    /// [`PROTOCOL_VERSION`].
+    pub fn is_compatible(&self) -> bool {
+        (1..=PROTOCOL_VERSION).contains(&self.0[0])
+    }
+
+    /// Compare two packets byte for byte, except for the timestamp of a `Ping`, `MemberJoin` or
+    /// `MemberLeave`.
+    pub fn eq_ignoring_timestamp(&self, other: &Packet) -> bool {
+        match self.try_op() {
+            Ok(Opcode::Ping | Opcode::MemberJoin | Opcode::MemberLeave) => {
+                self.0[..12] == other.0[..12] && self.0[20..] == other.0[20..]
+            }
+            _ => self.0 == other.0,
+        }
+    }
+
     /// Get the packet's op.
+    ///
+    /// Panics if the opcode byte is out of range. Use [`Packet::try_op`] on untrusted packets.
     pub fn op(&self) -> Opcode {
-        self.0[1].into()
+        self.try_op()
+            .unwrap_or_else(|_| panic!("Opcode `{}` out of range", self.0[1]))
+    }
+
+    /// Get the packet's op, or `UnknownOpcode` if the opcode byte is out of range.
+    pub fn try_op(&self) -> Result<Opcode, PacketError> {
+        Opcode::try_from(self.0[1])
     }
 
     /// Get the packet's version.
@@ -35,11 +484,69 @@ impl Packet {
         self.0[0]
     }
 
+    /// Get the packet's flags byte.
+    pub fn flags(&self) -> u8 {
+        self.0[2]
+    }
+
+    /// Check whether a flag is set.
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.0[2] & flag as u8 != 0
+    }
+
+    /// Set or clear a flag, leaving the others untouched.
+    pub fn set_flag(&mut self, flag: Flag, on: bool) {
+        if on {
+            self.0[2] |= flag as u8;
+        } else {
+            self.0[2] &= !(flag as u8);
+        }
+    }
+
+    /// Compute the CRC32 of everything before the checksum.
+    pub fn compute_checksum(&self) -> u32 {
+        let crc = self.0[..CHECKSUM_OFFSET].iter().fold(!0u32, |crc, &b| {
+            (0..8).fold(crc ^ b as u32, |crc, _| {
+                (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+            })
+        });
+        !crc
+    }
+
+    /// Store the checksum in the last 4 bytes. Call this after the packet is otherwise complete.
+    pub fn set_checksum(&mut self) {
+        let checksum = self.compute_checksum();
+        self.0[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    /// Check the stored checksum against the packet's bytes.
+    pub fn verify_checksum(&self) -> bool {
+        self.0[CHECKSUM_OFFSET..] == self.compute_checksum().to_le_bytes()
+    }
+
+    /// Get the bytes in `range`, or `None` if it's out of bounds.
+    pub fn field_bytes(&self, range: Range<usize>) -> Option<&[u8]> {
+        self.0.get(range)
+    }
+
     /// Get a packet snowflake starting at a given index.
     pub fn snowflake(&self, start: usize) -> [u8; 8] {
         self.0[start..start + 8].try_into().unwrap()
     }
 
+    /// Read the snowflake starting at `start` in the byte order of the packet's version.
+    ///
+    /// Every version up to and including 1 is little-endian. A version that changes the byte order
+    /// gets its own arm here, so readers never pick an order themselves.
+    pub fn snowflake_for_version(&self, start: usize) -> u64 {
+        let bytes = self.snowflake(start);
+        match self.version() {
+            0..=PROTOCOL_VERSION => u64::from_le_bytes(bytes),
+            // Versions newer than this crate are assumed to keep the latest known order.
+            _ => u64::from_le_bytes(bytes),
+        }
+    }
+
     /// Set the packet's op.
     pub fn set_op(&mut self, op: Opcode) {
         self.0[1] = op as u8;
@@ -54,12 +561,47 @@ impl Packet {
         self.0[start_offset..start_offset + 8].copy_from_slice(&sf);
     }
 
+    /// Pack `bits` into the content area of a `Message`, eight to a byte, least significant bit
+    /// first. The rest of the content area is zeroed, so no bits from an earlier, longer set
+    /// survive. Fails for any other opcode.
+    pub fn set_bitset(&mut self, bits: &[bool]) -> Result<(), PacketError> {
+        match self.try_op()? {
+            Opcode::Message => {}
+            t => return Err(PacketError::InvaidContent { t }),
+        }
+        let area = &mut self.0[CONTENT_OFFSET..CHECKSUM_OFFSET];
+        if bits.len() > area.len() * 8 {
+            return Err(PacketError::BadContent { t: Opcode::Message, offset: None });
+        }
+
+        area.fill(0);
+        for (byte, chunk) in area.iter_mut().zip(bits.chunks(8)) {
+            *byte = chunk
+                .iter()
+                .enumerate()
+                .fold(0, |acc, (i, &bit)| acc | (bit as u8) << i);
+        }
+        Ok(())
+    }
+
+    /// Read `len` bits packed by [`Packet::set_bitset`], or `None` for any opcode but `Message` or
+    /// if they don't fit in the content area.
+    pub fn bitset(&self, len: usize) -> Option<Vec<bool>> {
+        if self.0[1] != Opcode::Message as u8 {
+            return None;
+        }
+        let area = &self.0[CONTENT_OFFSET..CHECKSUM_OFFSET];
+        if len > area.len() * 8 {
+            return None;
+        }
+        Some((0..len).map(|i| area[i / 8] & (1 << (i % 8)) != 0).collect())
+    }
+
     /// Set the packet's content depending on the opcode.
     pub fn set_content(&mut self, content: PacketMessageContent) -> Result<(), PacketError> {
-        match self.0[1].into() {
-            Opcode::Message => {
-                let n = &self.0[..20];
-                self.0 = [n, &content.0].concat().try_into().unwrap();
+        match self.try_op()? {
+            op @ Opcode::Message => {
+                self.0[content_offset(op)..CHECKSUM_OFFSET].copy_from_slice(&content.0);
 
                 Ok(())
             }
@@ -68,39 +610,118 @@ impl Packet {
     }
 }
 
-impl Into<[u8; 1024]> for Packet {
-    fn into(self) -> [u8; 1024] {
+/// Types that can be written to the wire as a single frame.
+pub trait AsWireBytes {
+    fn to_wire(&self) -> BasePacket;
+}
+
+/// Types that can be read from a single frame off the wire.
+pub trait FromWireBytes: Sized {
+    fn from_wire(bytes: BasePacket) -> Result<Self, PacketError>;
+}
+
+impl AsWireBytes for Packet {
+    fn to_wire(&self) -> BasePacket {
         self.0
     }
 }
 
-impl From<u8> for Opcode {
-    fn from(op: u8) -> Self {
-        use self::Opcode::*;
+impl FromWireBytes for Packet {
+    /// Fails if the opcode byte is out of range, since [`Packet::op`] would panic on it.
+    fn from_wire(bytes: BasePacket) -> Result<Self, PacketError> {
+        Opcode::try_from(bytes[1])?;
+        Ok(Packet::new(bytes))
+    }
+}
+
+/// Shows the header and the decoded fields of the packet's opcode rather than the raw bytes, with
+/// message content cut to a short preview. `Login` tokens are left out so they stay out of logs.
+impl fmt::Debug for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("Packet");
+        s.field("version", &self.version());
+        let op = match self.try_op() {
+            Ok(op) => op,
+            Err(_) => return s.field("op", &self.0[1]).finish(),
+        };
+        s.field("op", &op.name());
+
+        if let Some(user_id) = self.user_id() {
+            s.field("user_id", &user_id);
+        }
+        if let Some(message_id) = self.message_id() {
+            s.field("message_id", &message_id);
+        }
+        if let Some(session) = self.session_id().or_else(|| self.login_ok_session()) {
+            s.field("session_id", &session.0);
+        }
+        if let Some(timestamp) = self.timestamp() {
+            s.field("timestamp", &timestamp);
+        }
+        if let Some(nonce) = self.ping_nonce() {
+            s.field("nonce", &nonce);
+        }
         match op {
+            Opcode::Message => s.field("content", &self.content_preview(40)),
+            Opcode::Error => s.field("code", &self.error_code()),
+            Opcode::Notice => s
+                .field("severity", &self.notice_severity())
+                .field("text", &self.notice_text().unwrap_or("<invalid UTF-8>")),
+            Opcode::Reaction => s
+                .field("target", &self.reaction_target().unwrap_or_default())
+                .field("emoji", &self.reaction_emoji().unwrap_or("<invalid UTF-8>")),
+            _ => &mut s,
+        };
+        s.finish()
+    }
+}
+
+impl From<Packet> for [u8; 1024] {
+    fn from(packet: Packet) -> Self {
+        packet.0
+    }
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = PacketError;
+
+    fn try_from(op: u8) -> Result<Self, PacketError> {
+        use self::Opcode::*;
+        Result::Ok(match op {
             0 => Ping,
             1 => Ok,
             2 => MemberJoin,
             3 => MemberLeave,
             4 => Message,
-            _ => panic!("Opcode `{}` out of range", op),
-        }
+            5 => Login,
+            6 => LoginOk,
+            7 => Goodbye,
+            8 => Error,
+            9 => Health,
+            10 => Notice,
+            11 => Reaction,
+            _ => return Err(PacketError::UnknownOpcode { op }),
+        })
     }
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Opcode {
     /// Ping packet byte layout  
     ///
     /// `3-11` - User ID `(8 bytes:snowflake)`
     ///
     /// `12-20` - timestamp `(8 bytes)`
+    ///
+    /// `21-29` - Nonce `(8 bytes)`
     Ping = 0,
 
     /// Ok packet byte layout
     ///
-    /// `3-1024` - `<empty>`
+    /// `3-21` - `<empty>`
+    ///
+    /// `21-29` - Nonce of the ping being answered `(8 bytes)`, zero otherwise
     ///
     /// It's okay :)
     Ok,
@@ -124,11 +745,24 @@ pub enum Opcode {
     ///
     /// `3-11` - User ID `(8 bytes:snowflake)`
     ///
+    /// `11` - Fragment index `(1 byte)`
+    ///
     /// `12-20` - Message ID `(8 bytes:snowflake)`
     ///
+    /// `20` - Fragment count `(1 byte)`, zero if the message is not fragmented
+    ///
     /// `21-29` - Session ID `(8 bytes:snowflake)`
     ///
-    /// `30-1024` - Message content `(994 bytes:string)`
+    /// `29` - Fragment padding `(1 byte)`, how many trailing bytes of a fragment's content are
+    /// padding rather than text. Zero on the last fragment, whose padding is trimmed as usual
+    ///
+    /// `30-1020` - Message content `(990 bytes:string)`
+    ///
+    /// A message too long for one packet is split with
+    /// [`fragment`](crate::base_types::fragment::fragment).
+    ///
+    /// This layout is authoritative. Messages in the older layout, with content at `20-1023`, can be
+    /// converted with [`Packet::migrate_legacy`].
     Message,
 
     /// Login packet byte layout
@@ -136,12 +770,170 @@ pub enum Opcode {
     /// `3-19` - User token `(16 bytes:token)`
     /// 
     /// Note: sending this packet will result in receiving a LoginOk packet unless there is already a session for this token
+    ///
+    /// If the token or the connection already has a session, the server answers with an `Error`
+    /// carrying `AlreadyLoggedIn` instead of reusing it.
     Login,
 
     /// LoginOk packet byte layout
     /// 
     /// `3-11` - Session ID `(8 bytes:snowflake)`
     LoginOk,
+
+    /// Goodbye packet byte layout
+    ///
+    /// `3-1024` - `<empty>`
+    ///
+    /// Sent by a client right before it closes the connection, so the server can tell a deliberate
+    /// quit from a dropped connection.
+    Goodbye,
+
+    /// Error packet byte layout
+    ///
+    /// `3` - Error code `(1 byte:ErrorCode)`
+    Error,
+
+    /// Health packet byte layout
+    ///
+    /// `3-1024` - `<empty>`
+    ///
+    /// A liveness probe for load balancers. It may be sent before logging in, is answered with an
+    /// `Ok`, and is never rate limited.
+    Health,
+
+    /// Notice packet byte layout
+    ///
+    /// `3` - Severity `(1 byte:Severity)`
+    ///
+    /// `4-1020` - Notice text `(1016 bytes:string)`
+    ///
+    /// A system notification from the server, e.g. an upcoming restart, rather than a user message.
+    Notice,
+
+    /// Reaction packet byte layout
+    ///
+    /// `3-11` - Target message ID `(8 bytes:snowflake)`
+    ///
+    /// `11-1020` - Emoji `(1009 bytes:string)`
+    Reaction,
+}
+
+/// The reason carried by an `Error` packet.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// A `Login` was sent on a connection that already has a session.
+    AlreadyLoggedIn = 1,
+    /// The connection sent packets faster than the server's rate limit allows. The packet was
+    /// dropped.
+    RateLimited = 2,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(ErrorCode::AlreadyLoggedIn),
+            2 => Some(ErrorCode::RateLimited),
+            _ => None,
+        }
+    }
+}
+
+/// How urgent a `Notice` is.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Info = 0,
+    Warning = 1,
+    Critical = 2,
+}
+
+impl Severity {
+    fn from_u8(severity: u8) -> Option<Self> {
+        match severity {
+            0 => Some(Severity::Info),
+            1 => Some(Severity::Warning),
+            2 => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+}
+
+/// Which side of a connection sends a given opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+    Bidirectional,
+}
+
+impl Opcode {
+    /// Every opcode, in discriminant order.
+    pub const ALL: [Opcode; 12] = [
+        Opcode::Ping,
+        Opcode::Ok,
+        Opcode::MemberJoin,
+        Opcode::MemberLeave,
+        Opcode::Message,
+        Opcode::Login,
+        Opcode::LoginOk,
+        Opcode::Goodbye,
+        Opcode::Error,
+        Opcode::Health,
+        Opcode::Notice,
+        Opcode::Reaction,
+    ];
+
+    /// Get the bytes that carry this opcode's fields. Everything after the header and outside this
+    /// range is padding.
+    pub fn payload_range(&self) -> Range<usize> {
+        use self::Opcode::*;
+        match self {
+            Ping => 3..29,
+            Ok => 21..29,
+            MemberJoin | MemberLeave => 3..20,
+            Message | Notice | Reaction => 3..CHECKSUM_OFFSET,
+            Login => 3..19,
+            LoginOk => 3..11,
+            Error => 3..4,
+            Goodbye | Health => 3..3,
+        }
+    }
+
+    /// Get which side of a connection sends this opcode.
+    pub fn direction(&self) -> Direction {
+        use self::Opcode::*;
+        match self {
+            Ping | Login | Goodbye | Health => Direction::ClientToServer,
+            Ok | MemberJoin | MemberLeave | LoginOk | Error | Notice => Direction::ServerToClient,
+            Message | Reaction => Direction::Bidirectional,
+        }
+    }
+
+    /// Get the opcode's protocol name, e.g. `"member_join"`. Unlike the `Debug` output this is
+    /// part of the protocol and will not change if the variant is renamed.
+    pub fn name(&self) -> &'static str {
+        use self::Opcode::*;
+        match self {
+            Ping => "ping",
+            Ok => "ok",
+            MemberJoin => "member_join",
+            MemberLeave => "member_leave",
+            Message => "message",
+            Login => "login",
+            LoginOk => "login_ok",
+            Goodbye => "goodbye",
+            Error => "error",
+            Health => "health",
+            Notice => "notice",
+            Reaction => "reaction",
+        }
+    }
+
+    /// Check whether the opcode carries string content, see [`content_len`].
+    pub fn has_content(&self) -> bool {
+        content_len(*self) > 0
+    }
 }
 
 #[derive(Debug)]
@@ -151,29 +943,138 @@ pub enum PacketError {
     /// For example, called `Packet::content(content)` on a `Opcode::Ping` packet
     InvaidContent { t: Opcode },
 
-    /// Packet content is too long
+    /// Packet content is too long or not valid UTF-8
     ///
-    /// For example, called `Packet::content(content)` on a String with a length greater than 1003
-    BadContent { t: Opcode },
+    /// For example, called `Packet::content(content)` on a String with a length greater than 990.
+    /// `offset` is the index of the first bad byte, if there is one, counted from the start of
+    /// whatever was being decoded: the packet, or the content for a [`PacketMessageContent`].
+    BadContent { t: Opcode, offset: Option<usize> },
+
+    /// The opcode byte does not name any opcode
+    UnknownOpcode { op: u8 },
+
+    /// The packet was built for a newer protocol version than `supported`, the one this crate
+    /// speaks
+    VersionMismatch { got: u8, supported: u8 },
+
+    /// The packet has version 0, usually because its buffer was never filled in
+    Uninitialized,
+}
+
+impl PacketError {
+    /// Get the index of the byte where the problem was found, if known.
+    pub fn offset(&self) -> Option<usize> {
+        use self::PacketError::*;
+        match self {
+            InvaidContent { .. } => None,
+            BadContent { offset, .. } => *offset,
+            UnknownOpcode { .. } => Some(1),
+            VersionMismatch { .. } | Uninitialized => Some(0),
+        }
+    }
 }
 
 impl Display for PacketError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         use self::PacketError::*;
         match self {
-            InvaidContent { t } => write!(
+            InvaidContent { t } => {
+                write!(f, "Tried to put content into a `{}` packet. Supported types are:", t.name())?;
+                let supported = Opcode::ALL.iter().filter(|op| op.has_content());
+                for (i, op) in supported.enumerate() {
+                    write!(f, "{} {}", if i == 0 { "" } else { "," }, op.name())?;
+                }
+                write!(f, ".")
+            }
+            BadContent { t, offset: None } => write!(f, "Malformed packet of type {:?}.", t),
+            BadContent { t, offset: Some(offset) } => {
+                write!(f, "Malformed packet of type {:?} at byte {}.", t, offset)
+            }
+            UnknownOpcode { op } => write!(f, "Unknown opcode `{}`.", op),
+            VersionMismatch { got, supported } => write!(
                 f,
-                "Tried to put content into a packet of type {:?}. Supported types are:
-                - Message",
-                t
+                "Unsupported protocol version `{}`, expected at most `{}`.",
+                got, supported
             ),
-            BadContent { t } => write!(f, "Malformed packet of type {:?}.", t),
+            Uninitialized => write!(f, "Uninitialized packet."),
         }
     }
 }
 
 impl Error for PacketError {}
 
+impl PacketMessageContent {
+    /// Format `args` straight into the content buffer. Fails if the output is longer than 990 bytes.
+    ///
+    /// Usually called through [`content!`](crate::content).
+    pub fn from_fmt(args: fmt::Arguments) -> Result<Self, PacketError> {
+        struct Buf {
+            inner: [u8; CONTENT_LEN],
+            len: usize,
+        }
+
+        impl Write for Buf {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                let end = self.len + s.len();
+                if end > self.inner.len() {
+                    return Err(fmt::Error);
+                }
+                self.inner[self.len..end].copy_from_slice(s.as_bytes());
+                self.len = end;
+                Ok(())
+            }
+        }
+
+        let mut buf = Buf {
+            inner: [0; CONTENT_LEN],
+            len: 0,
+        };
+        buf.write_fmt(args)
+            .map_err(|_| PacketError::BadContent { t: Opcode::Message, offset: None })?;
+        Ok(PacketMessageContent(buf.inner))
+    }
+
+    /// Like `from_str`, but cuts `s` down to the longest prefix that fits in 990 bytes instead of
+    /// failing. A multibyte character is never split.
+    pub fn from_str_truncating(s: &str) -> Self {
+        let end = s
+            .char_indices()
+            .map(|(i, c)| i + c.len_utf8())
+            .take_while(|&end| end <= CONTENT_LEN)
+            .last()
+            .unwrap_or(0);
+        s[..end].parse().unwrap()
+    }
+
+    /// Decode the content as UTF-8, stripping the trailing NUL padding.
+    pub fn content_str(&self) -> Result<&str, PacketError> {
+        self.content_str_with(Trim::default())
+    }
+
+    /// Decode the content as UTF-8, stripping its end according to `trim`.
+    pub fn content_str_with(&self, trim: Trim) -> Result<&str, PacketError> {
+        let keep = |b: &u8| match trim {
+            Trim::TrailingNul => *b != 0,
+            Trim::TrailingWhitespaceAndNul => *b != 0 && !b.is_ascii_whitespace(),
+            Trim::None => true,
+        };
+        let end = self.0.iter().rposition(keep).map_or(0, |i| i + 1);
+
+        std::str::from_utf8(&self.0[..end]).map_err(|e| PacketError::BadContent {
+            t: Opcode::Message,
+            offset: Some(e.valid_up_to()),
+        })
+    }
+}
+
+/// Build a [`PacketMessageContent`] with `format!` syntax, e.g. `content!("hello {}", name)`.
+#[macro_export]
+macro_rules! content {
+    ($($arg:tt)*) => {
+        $crate::base_types::packet::PacketMessageContent::from_fmt(format_args!($($arg)*))
+    };
+}
+
 impl FromStr for PacketMessageContent {
     type Err = PacketError;
 
@@ -182,13 +1083,13 @@ impl FromStr for PacketMessageContent {
         // let out: BasePacket = [n, &content.0].concat().try_into().unwrap();
 
         let b = s.as_bytes();
-        if b.len() > 994 {
-            return Err(PacketError::BadContent { t: Opcode::Message });
+        if b.len() > content_len(Opcode::Message) {
+            return Err(PacketError::BadContent { t: Opcode::Message, offset: None });
         }
-        let re = [0u8].repeat(994 - b.len());
+        let re = [0u8].repeat(content_len(Opcode::Message) - b.len());
         let uuw = re.as_slice();
 
-        let uw: [u8; 994] = [b, uuw].concat().try_into().unwrap();
+        let uw: [u8; CONTENT_LEN] = [b, uuw].concat().try_into().unwrap();
 
         Ok(PacketMessageContent(uw))
     }
@@ -209,18 +1110,583 @@ pub mod test {
         packet.set_snowflake(SNOWFLAKE.lock().generate_u8_u64(), 3);
         std::thread::sleep(Duration::from_secs(1));
 
-        packet.set_snowflake(SNOWFLAKE.lock().generate_u8_u64(), 11);
+        packet.set_snowflake(SNOWFLAKE.lock().generate_u8_u64(), 12);
         std::thread::sleep(Duration::from_secs(1));
 
-        packet.set_snowflake(SNOWFLAKE.lock().generate_u8_u64(), 30);
+        packet.set_snowflake(SNOWFLAKE.lock().generate_u8_u64(), 21);
         std::thread::sleep(Duration::from_secs(1));
 
         assert_eq!(packet.0[0], 0);
         assert_eq!(packet.0[1], 4);
-        assert_eq!(&packet.0[20..23], "uwu".as_bytes());
+        assert_eq!(&packet.0[30..33], "uwu".as_bytes());
 
         println!("{:?}", u64::from_le_bytes(packet.snowflake(3)));
-        println!("{:?}", u64::from_le_bytes(packet.snowflake(11)));
-        println!("{:?}", u64::from_le_bytes(packet.snowflake(30)));
+        println!("{:?}", u64::from_le_bytes(packet.snowflake(12)));
+        println!("{:?}", u64::from_le_bytes(packet.snowflake(21)));
+    }
+
+    #[test]
+    fn set_content_keeps_ids() {
+        let mut packet = Packet::blank(Opcode::Message);
+        packet.set_message_ids(1, 2, 3).unwrap();
+        packet.set_content("uwu".parse().unwrap()).unwrap();
+
+        assert_eq!(u64::from_le_bytes(packet.snowflake(3)), 1);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(12)), 2);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(21)), 3);
+        assert_eq!(packet.content().unwrap().content_str().unwrap(), "uwu");
+    }
+
+    #[test]
+    fn builders() {
+        let message = Packet::message(1, 2, SessionId(3), "hello").unwrap();
+        assert_eq!(message.version(), PROTOCOL_VERSION);
+        assert_eq!(message.user_id(), Some(1));
+        assert_eq!(message.message_id(), Some(2));
+        assert_eq!(message.session_id(), Some(SessionId(3)));
+        assert_eq!(message.message_content().unwrap(), "hello");
+        assert!(matches!(
+            Packet::message(1, 2, SessionId(3), &"a".repeat(995)),
+            Err(PacketError::BadContent { t: Opcode::Message, .. })
+        ));
+
+        let ping = Packet::ping(7, 1000);
+        assert_eq!(ping.op(), Opcode::Ping);
+        assert_eq!(u64::from_le_bytes(ping.snowflake(3)), 7);
+        assert_eq!(i64::from_le_bytes(ping.snowflake(12)), 1000);
+
+        let join = Packet::member_join(7, 1000);
+        let leave = Packet::member_leave(7, 1000);
+        assert_eq!(join.op(), Opcode::MemberJoin);
+        assert_eq!(leave.op(), Opcode::MemberLeave);
+        assert_eq!(join.0[3..20], leave.0[3..20]);
+    }
+
+    #[test]
+    fn login() {
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        let packet = Packet::login(token);
+        assert_eq!(packet.op(), Opcode::Login);
+        assert_eq!(packet.login_token(), Some(token));
+        assert_eq!(Packet::login_ok(SessionId(1)).login_token(), None);
+    }
+
+    #[test]
+    fn login_ok() {
+        let session = SessionId::generate(&mut SNOWFLAKE.lock());
+        let packet = Packet::login_ok(session);
+
+        assert_eq!(packet.version(), PROTOCOL_VERSION);
+        assert!(matches!(packet.op(), Opcode::LoginOk));
+        assert_eq!(packet.login_ok_session(), Some(session));
+
+        let mut other = Packet::new([0; 1024]);
+        other.set_op(Opcode::Ping);
+        assert_eq!(other.login_ok_session(), None);
+    }
+
+    #[test]
+    fn ping_nonce() {
+        let first = Packet::ping_with_nonce(7, 1);
+        let second = Packet::ping_with_nonce(7, 2);
+        assert_eq!(first.ping_nonce(), Some(1));
+        assert_eq!(second.ping_nonce(), Some(2));
+
+        // Replies arrive out of order, but can still be matched to their pings.
+        let replies = [Packet::ping_reply(&second), Packet::ping_reply(&first)];
+        for reply in &replies {
+            assert!(matches!(reply.op(), Opcode::Ok));
+        }
+        let pings = [&first, &second];
+        let matched: Vec<u64> = replies
+            .iter()
+            .map(|reply| {
+                let ping = pings.iter().find(|p| p.ping_nonce() == reply.ping_nonce()).unwrap();
+                ping.ping_nonce().unwrap()
+            })
+            .collect();
+        assert_eq!(matched, vec![2, 1]);
+
+        assert_eq!(Packet::login_ok(SessionId(1)).ping_nonce(), None);
+    }
+
+    #[test]
+    fn goodbye() {
+        assert_eq!(Opcode::try_from(Opcode::Goodbye as u8).unwrap(), Opcode::Goodbye);
+        assert_eq!(Opcode::Goodbye.direction(), Direction::ClientToServer);
+
+    }
+
+    #[test]
+    fn health() {
+        assert_eq!(Opcode::try_from(Opcode::Health as u8).unwrap(), Opcode::Health);
+        assert_eq!(Opcode::Health.direction(), Direction::ClientToServer);
+    }
+
+    #[test]
+    fn content_from_fmt() {
+        let name = "there";
+        let content = crate::content!("hello {}, {}", name, 42).unwrap();
+        assert_eq!(content.content_str().unwrap(), "hello there, 42");
+
+        assert!(crate::content!("{}", "a".repeat(CONTENT_LEN)).is_ok());
+        assert!(crate::content!("{}{}", "a".repeat(CONTENT_LEN), 1).is_err());
+    }
+
+    #[test]
+    fn wire_bytes() {
+        fn roundtrip<T: AsWireBytes + FromWireBytes>(t: T) -> T {
+            T::from_wire(t.to_wire()).unwrap()
+        }
+
+        let packet = Packet::login_ok(SessionId(42));
+        let back = roundtrip(Packet::login_ok(SessionId(42)));
+        assert_eq!(back.0, packet.0);
+        assert_eq!(back.login_ok_session(), Some(SessionId(42)));
+
+        let mut bytes = [0; 1024];
+        bytes[1] = 200;
+        assert!(matches!(
+            Packet::from_wire(bytes),
+            Err(PacketError::UnknownOpcode { op: 200 })
+        ));
+    }
+
+    #[test]
+    fn unknown_opcode_readers() {
+        let mut packet = Packet::message(1, 2, SessionId(3), "hello").unwrap();
+        packet.0[1] = 200;
+        for result in [
+            packet.message_content().map(drop),
+            packet.notice_text().map(drop),
+            packet.reaction_emoji().map(drop),
+            packet.set_content("hi".parse().unwrap()),
+        ] {
+            assert!(matches!(result, Err(PacketError::UnknownOpcode { op: 200 })));
+        }
+
+        let other = Packet::new(packet.0);
+        assert_eq!(packet.content_hash(), other.content_hash());
+        assert!(packet.semantic_eq(&other));
+    }
+
+    #[test]
+    fn content_hash() {
+        let message = |message_id: u64, text: &[u8]| {
+            let mut packet = Packet::new([0; 1024]);
+            packet.set_op(Opcode::Message);
+            packet.set_snowflake(7u64.to_le_bytes(), 3);
+            packet.set_snowflake(message_id.to_le_bytes(), 12);
+            packet.0[CONTENT_OFFSET..CONTENT_OFFSET + text.len()].copy_from_slice(text);
+            packet
+        };
+
+        assert_eq!(message(1, b"hello").content_hash(), message(2, b"hello").content_hash());
+        assert_ne!(message(1, b"hello").content_hash(), message(1, b"hellp").content_hash());
+        assert_ne!(
+            message(1, b"").content_hash(),
+            Packet::new([0; 1024]).content_hash()
+        );
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Packet::login_ok(SessionId(1)).validate().is_ok());
+
+        let mut packet = Packet::login_ok(SessionId(1));
+        packet.set_version(PROTOCOL_VERSION + 1);
+        assert!(matches!(packet.validate(), Err(PacketError::VersionMismatch { .. })));
+
+        let mut packet = Packet::login_ok(SessionId(1));
+        packet.0[1] = 200;
+        assert!(matches!(packet.validate(), Err(PacketError::UnknownOpcode { op: 200 })));
+
+        assert!(matches!(Packet::new([0; 1024]).validate(), Err(PacketError::Uninitialized)));
+        let mut packet = Packet::login_ok(SessionId(1));
+        packet.set_version(0);
+        assert!(matches!(packet.validate(), Err(PacketError::Uninitialized)));
+    }
+
+    #[test]
+    fn reset() {
+        let mut packet = Packet::new([0xaa; 1024]);
+        packet.reset(Opcode::Ping);
+        packet.set_snowflake(7u64.to_le_bytes(), 3);
+
+        assert_eq!(packet.version(), PROTOCOL_VERSION);
+        assert_eq!(packet.op(), Opcode::Ping);
+        assert_eq!(packet.flags(), 0);
+        assert!(packet.0[29..].iter().all(|&b| b == 0));
+        assert_eq!(u64::from_le_bytes(packet.snowflake(3)), 7);
+
+        let mut packet = Packet::new([0xaa; 1024]);
+        packet.reset(Opcode::Ok);
+        assert!(packet.0[3..21].iter().all(|&b| b == 0));
+        assert!(packet.0[29..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn version_compatibility() {
+        let mut packet = Packet::login_ok(SessionId(1));
+        packet.set_version(0);
+        assert!(!packet.is_compatible());
+        assert!(matches!(packet.validate(), Err(PacketError::Uninitialized)));
+
+        packet.set_version(1);
+        assert!(packet.is_compatible());
+        assert!(packet.validate().is_ok());
+
+        packet.set_version(255);
+        assert!(!packet.is_compatible());
+        assert!(matches!(
+            packet.validate(),
+            Err(PacketError::VersionMismatch { got: 255, supported: PROTOCOL_VERSION })
+        ));
+    }
+
+    #[test]
+    fn error_offset() {
+        let mut content: PacketMessageContent = "hello".parse().unwrap();
+        content.0[3] = 0xff;
+        let err = content.content_str().unwrap_err();
+        assert!(matches!(err, PacketError::BadContent { t: Opcode::Message, offset: Some(3) }));
+        assert_eq!(err.offset(), Some(3));
+
+        let mut notice = Packet::notice(Severity::Info, "hello").unwrap();
+        notice.0[6] = 0xff;
+        assert_eq!(notice.notice_text().unwrap_err().offset(), Some(6));
+
+        let mut packet = Packet::login_ok(SessionId(1));
+        packet.0[1] = 200;
+        assert_eq!(packet.validate().unwrap_err().offset(), Some(1));
+        assert_eq!("a".repeat(995).parse::<PacketMessageContent>().err().unwrap().offset(), None);
+    }
+
+    #[test]
+    fn set_message_ids() {
+        let mut packet = Packet::blank(Opcode::Message);
+        packet.set_message_ids(1, 2, 3).unwrap();
+        assert_eq!(u64::from_le_bytes(packet.snowflake(3)), 1);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(12)), 2);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(21)), 3);
+
+        let mut ping = Packet::ping_with_nonce(7, 8);
+        assert!(matches!(
+            ping.set_message_ids(1, 2, 3),
+            Err(PacketError::InvaidContent { t: Opcode::Ping })
+        ));
+        assert_eq!(ping.ping_nonce(), Some(8));
+        assert_eq!(ping.user_id(), Some(7));
+    }
+
+    #[test]
+    fn snowflake_for_version() {
+        let mut packet = Packet::blank(Opcode::Ping);
+        packet.0[3..11].copy_from_slice(&[1, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(packet.version(), 1);
+        assert_eq!(packet.snowflake_for_version(3), 0x0200_0000_0000_0001);
+    }
+
+    #[test]
+    fn try_op() {
+        for op in Opcode::ALL {
+            assert_eq!(Opcode::try_from(op as u8).unwrap(), op);
+        }
+        assert_eq!(Opcode::try_from(5).unwrap(), Opcode::Login);
+        assert_eq!(Opcode::try_from(6).unwrap(), Opcode::LoginOk);
+        assert!(matches!(Opcode::try_from(200), Err(PacketError::UnknownOpcode { op: 200 })));
+
+        let mut packet = Packet::new([0; 1024]);
+        packet.0[1] = 200;
+        assert!(matches!(packet.try_op(), Err(PacketError::UnknownOpcode { op: 200 })));
+    }
+
+    #[test]
+    fn message_content() {
+        for text in ["hello", "héllo wörld 👋", "", "a\0b"] {
+            let mut packet = Packet::blank(Opcode::Message);
+            packet.set_content(text.parse().unwrap()).unwrap();
+            assert_eq!(packet.message_content().unwrap(), text);
+        }
+
+        let mut packet = Packet::blank(Opcode::Message);
+        packet.set_content("hello".parse().unwrap()).unwrap();
+        packet.0[CONTENT_OFFSET + 2] = 0xff;
+        assert_eq!(packet.message_content().unwrap_err().offset(), Some(CONTENT_OFFSET + 2));
+
+        assert!(matches!(
+            Packet::login_ok(SessionId(1)).message_content(),
+            Err(PacketError::InvaidContent { t: Opcode::LoginOk })
+        ));
+    }
+
+    #[test]
+    fn eq_ignoring_timestamp() {
+        let mut a = Packet::ping_with_nonce(7, 1);
+        let mut b = Packet::ping_with_nonce(7, 1);
+        a.set_snowflake(1000i64.to_le_bytes(), 12);
+        b.set_snowflake(2000i64.to_le_bytes(), 12);
+        assert!(a.eq_ignoring_timestamp(&b));
+
+        b.set_snowflake(8u64.to_le_bytes(), 3);
+        assert!(!a.eq_ignoring_timestamp(&b));
+
+        let mut a = Packet::login_ok(SessionId(1));
+        let b = Packet::login_ok(SessionId(1));
+        assert!(a.eq_ignoring_timestamp(&b));
+        a.0[15] = 1;
+        assert!(!a.eq_ignoring_timestamp(&b));
+    }
+
+    #[test]
+    fn wire_frame_count() {
+        assert_eq!(Packet::wire_frame_count(""), 1);
+        assert_eq!(Packet::wire_frame_count(&"a".repeat(990)), 1);
+        assert_eq!(Packet::wire_frame_count(&"a".repeat(991)), 2);
+        assert_eq!(Packet::wire_frame_count(&"a".repeat(3000)), 4);
+        // 247 four-byte characters fill 988 bytes, so the next one starts a new frame.
+        assert_eq!(Packet::wire_frame_count(&"👋".repeat(248)), 2);
+    }
+
+    #[test]
+    fn from_str_truncating() {
+        let ascii = "a".repeat(991);
+        let content = PacketMessageContent::from_str_truncating(&ascii);
+        assert_eq!(content.content_str().unwrap(), &ascii[..990]);
+
+        // 248 four-byte characters are 992 bytes, so the last one is dropped whole.
+        let emoji = "👋".repeat(248);
+        let content = PacketMessageContent::from_str_truncating(&emoji);
+        assert_eq!(content.content_str().unwrap(), "👋".repeat(247));
+
+        let short = PacketMessageContent::from_str_truncating("hi");
+        assert_eq!(short.content_str().unwrap(), "hi");
+    }
+
+    #[test]
+    fn checksum() {
+        let mut packet = Packet::message(1, 2, SessionId(3), "hello").unwrap();
+        assert!(!packet.verify_checksum());
+
+        packet.set_checksum();
+        assert!(packet.verify_checksum());
+        assert_eq!(Packet::new([0; 1024]).compute_checksum(), 0x67bb_af86);
+
+        packet.0[CONTENT_OFFSET] ^= 1;
+        assert!(!packet.verify_checksum());
+    }
+
+    #[test]
+    fn content_preview() {
+        let mut packet = Packet::blank(Opcode::Message);
+        let text = "hey\n\n  are you\tcoming\u{7} to the thing tonight?\r\nlet me know";
+        packet.set_content(text.parse().unwrap()).unwrap();
+
+        assert_eq!(
+            packet.content_preview(100),
+            "hey are you coming to the thing tonight? let me know"
+        );
+        assert_eq!(packet.content_preview(12), "hey are you…");
+        assert_eq!(packet.content_preview(0), "");
+        assert_eq!(Packet::ping_with_nonce(1, 2).content_preview(10), "");
+    }
+
+    #[test]
+    fn notice() {
+        let packet = Packet::notice(Severity::Warning, "restarting in 5 minutes").unwrap();
+        assert_eq!(packet.op(), Opcode::Notice);
+        assert_eq!(packet.notice_severity(), Some(Severity::Warning));
+        assert_eq!(packet.notice_text().unwrap(), "restarting in 5 minutes");
+        assert_eq!(Opcode::Notice.direction(), Direction::ServerToClient);
+
+        assert!(Packet::notice(Severity::Info, &"a".repeat(1016)).is_ok());
+        assert!(Packet::notice(Severity::Info, &"a".repeat(1017)).is_err());
+        assert_eq!(Packet::error(ErrorCode::AlreadyLoggedIn).notice_severity(), None);
+        assert!(Packet::error(ErrorCode::AlreadyLoggedIn).notice_text().is_err());
+    }
+
+    #[test]
+    fn opcode_names() {
+        let names: Vec<&str> = Opcode::ALL.iter().map(Opcode::name).collect();
+        assert_eq!(
+            names,
+            [
+                "ping", "ok", "member_join", "member_leave", "message", "login", "login_ok",
+                "goodbye", "error", "health", "notice", "reaction",
+            ]
+        );
+
+        assert!(Opcode::Message.has_content());
+        assert!(!Opcode::Ping.has_content());
+        assert_eq!(
+            PacketError::InvaidContent { t: Opcode::Ping }.to_string(),
+            "Tried to put content into a `ping` packet. Supported types are: message, notice, reaction."
+        );
+    }
+
+    #[test]
+    fn debug() {
+        let message = Packet::message(1, 2, SessionId(3), "hello").unwrap();
+        assert_eq!(
+            format!("{:?}", message),
+            r#"Packet { version: 1, op: "message", user_id: 1, message_id: 2, session_id: 3, content: "hello" }"#
+        );
+
+        let long = Packet::message(1, 2, SessionId(3), &"a".repeat(100)).unwrap();
+        let preview = format!(r#"content: "{}…" }}"#, "a".repeat(39));
+        assert!(format!("{:?}", long).ends_with(&preview));
+
+        let login = Packet::login(UserToken::from_bytes(*b"0123456789abcdef"));
+        assert_eq!(format!("{:?}", login), r#"Packet { version: 1, op: "login" }"#);
+
+        let mut unknown = Packet::login_ok(SessionId(1));
+        unknown.0[1] = 200;
+        assert_eq!(format!("{:?}", unknown), "Packet { version: 1, op: 200 }");
+    }
+
+    #[test]
+    fn content_table() {
+        for op in [Opcode::Message, Opcode::Notice, Opcode::Reaction] {
+            assert!(content_len(op) > 0);
+            assert_eq!(content_offset(op) + content_len(op), CHECKSUM_OFFSET);
+            assert_eq!(op.payload_range().end, CHECKSUM_OFFSET);
+        }
+        assert_eq!(CHECKSUM_OFFSET + 4, 1024);
+        assert_eq!(content_len(Opcode::Message), CONTENT_LEN);
+        assert_eq!(content_len(Opcode::Ping), 0);
+    }
+
+    #[test]
+    fn reaction() {
+        let packet = Packet::reaction(42, "👍").unwrap();
+        assert_eq!(packet.op(), Opcode::Reaction);
+        assert_eq!(packet.reaction_target(), Some(42));
+        assert_eq!(packet.reaction_emoji().unwrap(), "👍");
+        assert_eq!(Packet::from_wire(packet.to_wire()).unwrap().reaction_emoji().unwrap(), "👍");
+
+        assert!(Packet::reaction(1, &"a".repeat(1009)).is_ok());
+        assert!(Packet::reaction(1, &"a".repeat(1010)).is_err());
+        assert_eq!(Packet::notice(Severity::Info, "hi").unwrap().reaction_target(), None);
+        assert!(Packet::error(ErrorCode::AlreadyLoggedIn).reaction_emoji().is_err());
+    }
+
+    #[test]
+    fn error() {
+        let packet = Packet::error(ErrorCode::AlreadyLoggedIn);
+        assert_eq!(packet.op(), Opcode::Error);
+        assert_eq!(packet.error_code(), Some(ErrorCode::AlreadyLoggedIn));
+        assert_eq!(Packet::login_ok(SessionId(1)).error_code(), None);
+    }
+
+    #[test]
+    fn flags() {
+        let mut packet = Packet::new([0; 1024]);
+
+        packet.set_flag(Flag::Compressed, true);
+        packet.set_flag(Flag::Continuation, true);
+        assert!(packet.has_flag(Flag::Compressed));
+        assert!(!packet.has_flag(Flag::Binary));
+        assert!(packet.has_flag(Flag::Continuation));
+        assert_eq!(packet.flags(), 0x05);
+
+        packet.set_flag(Flag::Compressed, false);
+        assert!(!packet.has_flag(Flag::Compressed));
+        assert!(packet.has_flag(Flag::Continuation));
+        assert_eq!(packet.flags(), 0x04);
+    }
+
+    #[test]
+    fn bitset() {
+        let mut packet = Packet::new([0; 1024]);
+        packet.set_op(Opcode::Message);
+
+        let bits: Vec<bool> = (0..100).map(|i| i % 3 == 0).collect();
+        packet.set_bitset(&bits).unwrap();
+        assert_eq!(packet.bitset(100).unwrap(), bits);
+
+        assert!(packet.set_bitset(&[true; CONTENT_LEN * 8 + 1]).is_err());
+        assert!(packet.bitset(CONTENT_LEN * 8 + 1).is_none());
+
+        // A shorter set leaves none of the earlier bits behind.
+        packet.set_bitset(&[true; 4]).unwrap();
+        assert_eq!(packet.bitset(100).unwrap(), (0..100).map(|i| i < 4).collect::<Vec<_>>());
+
+        let mut ping = Packet::ping_with_nonce(7, 1);
+        assert!(matches!(
+            ping.set_bitset(&[true; 16]),
+            Err(PacketError::InvaidContent { t: Opcode::Ping })
+        ));
+        assert_eq!(ping.0[CONTENT_OFFSET], 0);
+        assert!(ping.bitset(16).is_none());
+    }
+
+    #[test]
+    fn semantic_eq() {
+        let a = Packet::ping_with_nonce(7, 1);
+        let mut b = Packet::new(a.0);
+        b.0[1000] = 0xff;
+
+        assert!(a.semantic_eq(&b));
+        assert_ne!(a.0, b.0);
+
+        b.0[21] = 0xff;
+        assert!(!a.semantic_eq(&b));
+    }
+
+    #[test]
+    fn field_bytes() {
+        let packet = Packet::ping_with_nonce(7, 1);
+
+        assert_eq!(packet.field_bytes(3..11), Some(&7u64.to_le_bytes()[..]));
+        assert_eq!(packet.field_bytes(1020..1025), None);
+        assert_eq!(packet.field_bytes(2000..2008), None);
+    }
+
+    #[test]
+    fn migrate_legacy() {
+        let mut old = [0; 1024];
+        old[0] = PROTOCOL_VERSION;
+        old[1] = Opcode::Message as u8;
+        old[3..11].copy_from_slice(&7u64.to_le_bytes());
+        old[12..20].copy_from_slice(&8u64.to_le_bytes());
+        old[20..25].copy_from_slice(b"hello");
+
+        let packet = Packet::migrate_legacy(old);
+        assert_eq!(packet.message_content().unwrap(), "hello");
+        assert_eq!(u64::from_le_bytes(packet.snowflake(3)), 7);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(12)), 8);
+        assert_eq!(u64::from_le_bytes(packet.snowflake(21)), 0);
+
+        // Content that doesn't fit is cut at a character boundary. Legacy packets never set a
+        // version, and stray flag or padding bytes must not turn into fragment fields.
+        let mut old = [0; 1024];
+        old[1] = Opcode::Message as u8;
+        old[2] = 0xff;
+        old[11] = 3;
+        let long = "é".repeat(501);
+        old[20..1022].copy_from_slice(long.as_bytes());
+
+        let packet = Packet::migrate_legacy(old);
+        assert_eq!(packet.message_content().unwrap(), "é".repeat(495));
+        assert!(packet.validate().is_ok());
+        assert_eq!(packet.flags(), 0);
+        assert_eq!(packet.fragment_index(), Some(0));
+        assert_eq!(packet.fragment_count(), Some(0));
+    }
+
+    #[test]
+    fn trim() {
+        let content: PacketMessageContent = "hi \0there \n ".parse().unwrap();
+
+        assert_eq!(content.content_str().unwrap(), "hi \0there \n ");
+        assert_eq!(content.content_str_with(Trim::TrailingNul).unwrap(), "hi \0there \n ");
+        assert_eq!(
+            content.content_str_with(Trim::TrailingWhitespaceAndNul).unwrap(),
+            "hi \0there"
+        );
+
+        let raw = content.content_str_with(Trim::None).unwrap();
+        assert_eq!(raw.len(), CONTENT_LEN);
+        assert!(raw.starts_with("hi \0there \n \0"));
     }
 }
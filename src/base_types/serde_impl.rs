@@ -0,0 +1,227 @@
+//! `serde` support, enabled with the `serde` feature.
+
+use super::packet::{trim_nul, Opcode, Packet};
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+const PACKET_FIELDS: &[&str] = &["version", "op", "flags", "body"];
+
+fn opcode_name(op: Opcode) -> &'static str {
+    use self::Opcode::*;
+    match op {
+        Ping => "Ping",
+        Ok => "Ok",
+        MemberJoin => "MemberJoin",
+        MemberLeave => "MemberLeave",
+        Message => "Message",
+        Login => "Login",
+        LoginOk => "LoginOk",
+        Goodbye => "Goodbye",
+        Error => "Error",
+        Health => "Health",
+        Notice => "Notice",
+        Reaction => "Reaction",
+    }
+}
+
+/// Opcodes serialize as their name, e.g. `"Message"`.
+impl Serialize for Opcode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(opcode_name(*self))
+    }
+}
+
+/// Opcodes deserialize from either their name or their numeric byte.
+impl<'de> Deserialize<'de> for Opcode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OpcodeVisitor;
+
+        impl<'de> de::Visitor<'de> for OpcodeVisitor {
+            type Value = Opcode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an opcode name or byte")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Opcode, E> {
+                Opcode::ALL
+                    .into_iter()
+                    .find(|&op| opcode_name(op) == v)
+                    .ok_or_else(|| E::unknown_variant(v, &[]))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Opcode, E> {
+                Opcode::ALL
+                    .into_iter()
+                    .find(|&op| op as u64 == v)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Opcode, E> {
+                match u64::try_from(v) {
+                    Ok(v) => self.visit_u64(v),
+                    Err(_) => Err(E::invalid_value(de::Unexpected::Signed(v), &self)),
+                }
+            }
+        }
+
+        deserializer.deserialize_any(OpcodeVisitor)
+    }
+}
+
+/// A packet's opcode byte, which may not be a known opcode. Known opcodes go by their name, and
+/// any other byte by its number, so malformed packets can still be logged and read back.
+struct OpcodeByte(u8);
+
+impl Serialize for OpcodeByte {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match Opcode::try_from(self.0) {
+            Ok(op) => op.serialize(serializer),
+            Err(_) => serializer.serialize_u8(self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OpcodeByte {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OpcodeByteVisitor;
+
+        impl<'de> de::Visitor<'de> for OpcodeByteVisitor {
+            type Value = OpcodeByte;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an opcode name or byte")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<OpcodeByte, E> {
+                Opcode::ALL
+                    .into_iter()
+                    .find(|&op| opcode_name(op) == v)
+                    .map(|op| OpcodeByte(op as u8))
+                    .ok_or_else(|| E::unknown_variant(v, &[]))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<OpcodeByte, E> {
+                u8::try_from(v)
+                    .map(OpcodeByte)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<OpcodeByte, E> {
+                u8::try_from(v)
+                    .map(OpcodeByte)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(OpcodeByteVisitor)
+    }
+}
+
+/// A packet is a struct of its header fields and a `body` holding bytes `3-1024` as hex, without
+/// the trailing zero bytes. An unknown opcode is written as its number.
+impl Serialize for Packet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = trim_nul(&self.0[3..]).iter().map(|b| format!("{:02x}", b)).collect();
+
+        let mut state = serializer.serialize_struct("Packet", 4)?;
+        state.serialize_field("version", &self.version())?;
+        state.serialize_field("op", &OpcodeByte(self.0[1]))?;
+        state.serialize_field("flags", &self.flags())?;
+        state.serialize_field("body", &hex)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Packet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PacketVisitor;
+
+        impl<'de> de::Visitor<'de> for PacketVisitor {
+            type Value = Packet;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a packet")
+            }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Packet, A::Error> {
+                let (mut version, mut op, mut flags, mut body) = (None, None, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "version" => version = Some(map.next_value::<u8>()?),
+                        "op" => op = Some(map.next_value::<OpcodeByte>()?.0),
+                        "flags" => flags = Some(map.next_value::<u8>()?),
+                        "body" => body = Some(map.next_value::<String>()?),
+                        _ => return Err(de::Error::unknown_field(&key, PACKET_FIELDS)),
+                    }
+                }
+                let version = version.ok_or_else(|| de::Error::missing_field("version"))?;
+                let op = op.ok_or_else(|| de::Error::missing_field("op"))?;
+                let flags = flags.ok_or_else(|| de::Error::missing_field("flags"))?;
+                let body = body.ok_or_else(|| de::Error::missing_field("body"))?;
+
+                if body.len() % 2 != 0 || body.len() > 2 * 1021 {
+                    return Err(de::Error::invalid_length(body.len(), &"at most 2042 hex digits"));
+                }
+                let mut packet = Packet::new([0; 1024]);
+                packet.0[0] = version;
+                packet.0[1] = op;
+                packet.0[2] = flags;
+                for (i, pair) in body.as_bytes().chunks(2).enumerate() {
+                    let pair = std::str::from_utf8(pair).map_err(de::Error::custom)?;
+                    packet.0[3 + i] = u8::from_str_radix(pair, 16).map_err(de::Error::custom)?;
+                }
+                Ok(packet)
+            }
+        }
+
+        deserializer.deserialize_struct("Packet", PACKET_FIELDS, PacketVisitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opcode() {
+        for op in Opcode::ALL {
+            let json = serde_json::to_string(&op).unwrap();
+            assert_eq!(json, format!("\"{}\"", opcode_name(op)));
+            assert_eq!(serde_json::from_str::<Opcode>(&json).unwrap(), op);
+            assert_eq!(serde_json::from_str::<Opcode>(&(op as u8).to_string()).unwrap(), op);
+        }
+
+        assert!(serde_json::from_str::<Opcode>("\"Pong\"").is_err());
+        assert!(serde_json::from_str::<Opcode>("255").is_err());
+    }
+
+    #[test]
+    fn packet() {
+        use crate::base_types::{auth::SessionId, packet::Flag};
+
+        let mut packet = Packet::message(1, 2, SessionId(3), "hello").unwrap();
+        packet.set_flag(Flag::Binary, true);
+
+        let json = serde_json::to_string(&packet).unwrap();
+        assert!(json.starts_with(r#"{"version":1,"op":"Message","flags":2,"body":"01"#));
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap().0, packet.0);
+
+        let empty = Packet::login_ok(SessionId(0));
+        let json = serde_json::to_string(&empty).unwrap();
+        assert_eq!(json, r#"{"version":1,"op":"LoginOk","flags":0,"body":""}"#);
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap().0, empty.0);
+
+        // Malformed packets are worth logging too, so an unknown opcode goes by its number.
+        let mut unknown = Packet::message(1, 2, SessionId(3), "?").unwrap();
+        unknown.0[1] = 200;
+        let json = serde_json::to_string(&unknown).unwrap();
+        assert!(json.starts_with(r#"{"version":1,"op":200,"flags":0,"body":"01"#));
+        assert_eq!(serde_json::from_str::<Packet>(&json).unwrap().0, unknown.0);
+        let too_big = r#"{"version":1,"op":256,"flags":0,"body":""}"#;
+        assert!(serde_json::from_str::<Packet>(too_big).is_err());
+
+        let bad_hex = r#"{"version":1,"op":"Ok","flags":0,"body":"zz"}"#;
+        assert!(serde_json::from_str::<Packet>(bad_hex).is_err());
+    }
+}
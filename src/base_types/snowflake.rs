@@ -1,6 +1,89 @@
-use chrono::Utc;
+use chrono::{DateTime, TimeZone, Utc};
 use parking_lot::Mutex;
-use std::sync::Arc;
+use std::{error::Error, fmt::Display, sync::Arc};
+
+/// How many bits of an ID hold the timestamp.
+const TIMESTAMP_BITS: u32 = 41;
+
+/// The largest worker or datacenter ID, as each has 5 bits.
+const MAX_NODE_ID: i64 = 31;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnowflakeError {
+    /// The worker ID does not fit in its 5 bits
+    WorkerIdOutOfRange(i64),
+
+    /// The datacenter ID does not fit in its 5 bits
+    DatacenterIdOutOfRange(i64),
+}
+
+impl Display for SnowflakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use self::SnowflakeError::*;
+        match self {
+            WorkerIdOutOfRange(id) => write!(f, "Worker ID `{}` is not in 0..={}.", id, MAX_NODE_ID),
+            DatacenterIdOutOfRange(id) => {
+                write!(f, "Datacenter ID `{}` is not in 0..={}.", id, MAX_NODE_ID)
+            }
+        }
+    }
+}
+
+impl Error for SnowflakeError {}
+
+/// How many bits each field of an ID takes up, from most to least significant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnowflakeLayout {
+    pub timestamp_bits: u32,
+    pub worker_bits: u32,
+    pub datacenter_bits: u32,
+    pub sequence_bits: u32,
+}
+
+/// The layout every generator uses.
+pub const LAYOUT: SnowflakeLayout = SnowflakeLayout {
+    timestamp_bits: TIMESTAMP_BITS,
+    worker_bits: 5,
+    datacenter_bits: 5,
+    sequence_bits: 12,
+};
+
+/// A generator's settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnowflakeConfig {
+    /// Unix time in milliseconds.
+    pub epoch: i64,
+    pub worker_id: i64,
+    pub datacenter_id: i64,
+    pub layout: SnowflakeLayout,
+}
+
+/// The fields packed into an ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnowflakeParts {
+    /// When the ID was generated.
+    pub timestamp: DateTime<Utc>,
+    pub worker_id: i64,
+    pub datacenter_id: i64,
+    pub sequence: i64,
+}
+
+/// Split `id` into its fields, given the epoch in milliseconds of the generator that made it.
+pub fn decode_with_epoch(id: i64, epoch: i64) -> SnowflakeParts {
+    SnowflakeParts {
+        timestamp: Utc.timestamp_millis_opt((id >> 22) + epoch).unwrap(),
+        worker_id: (id >> 17) & MAX_NODE_ID,
+        datacenter_id: (id >> 12) & MAX_NODE_ID,
+        sequence: id & 0xfff,
+    }
+}
+
+#[derive(Debug)]
+enum Clock {
+    System,
+    /// Starts at the given Unix time in milliseconds and advances by 1ms each time it is read.
+    Deterministic(i64),
+}
 
 #[derive(Debug)]
 pub struct Snowflake {
@@ -9,50 +92,231 @@ pub struct Snowflake {
     datacenter_id: i64,
     sequence: i64,
     time: Arc<Mutex<i64>>,
+    clock: Clock,
 }
 
 impl Default for Snowflake {
     fn default() -> Snowflake {
         Snowflake {
-            epoch: 1_573_948_800,
+            epoch: 1_573_948_800_000,
             worker_id: 1,
             datacenter_id: 1,
             sequence: 0,
             time: Arc::new(Mutex::new(0)),
+            clock: Clock::System,
         }
     }
 }
 
 impl Snowflake {
-    pub fn new(epoch: i64, worker_id: i64, datacenter_id: i64) -> Snowflake {
-        Self {
+    /// `epoch` is a Unix time in milliseconds.
+    ///
+    /// Fails if `worker_id` or `datacenter_id` is outside `0..=31`, since either would spill into
+    /// the neighbouring field and make IDs from different nodes collide.
+    pub fn new(epoch: i64, worker_id: i64, datacenter_id: i64) -> Result<Snowflake, SnowflakeError> {
+        if !(0..=MAX_NODE_ID).contains(&worker_id) {
+            return Err(SnowflakeError::WorkerIdOutOfRange(worker_id));
+        }
+        if !(0..=MAX_NODE_ID).contains(&datacenter_id) {
+            return Err(SnowflakeError::DatacenterIdOutOfRange(datacenter_id));
+        }
+        Ok(Self {
             epoch,
             worker_id,
             datacenter_id,
             ..Default::default()
+        })
+    }
+
+    /// A generator whose clock starts at `start_ms` (Unix time in milliseconds) and advances by
+    /// exactly 1ms per `generate` call, so it always produces the same sequence of IDs.
+    pub fn deterministic(start_ms: i64) -> Snowflake {
+        Self {
+            clock: Clock::Deterministic(start_ms),
+            ..Default::default()
         }
     }
 
+    /// Generate an ID.
+    ///
+    /// Panics if the time since the epoch no longer fits in the 41 timestamp bits, rather than
+    /// wrapping and colliding with earlier IDs.
     pub fn generate(&mut self) -> i64 {
-        let mut last_timestamp = self.time.lock();
+        let time = self.time.clone();
+        let mut last_timestamp = time.lock();
+        self.next_id(&mut last_timestamp)
+    }
+
+    /// Generate `n` unique, increasing IDs while holding the lock once, rather than once per ID.
+    pub fn reserve(&mut self, n: usize) -> Vec<i64> {
+        let time = self.time.clone();
+        let mut last_timestamp = time.lock();
+        (0..n).map(|_| self.next_id(&mut last_timestamp)).collect()
+    }
+
+    /// The whole step runs under the time lock, so the timestamp and sequence always move together.
+    fn next_id(&mut self, last_timestamp: &mut i64) -> i64 {
         let mut timestamp = self.get_time();
-        if timestamp == *last_timestamp {
+        if timestamp <= *last_timestamp {
+            // Still in the last millisecond, or the clock stepped back: keep counting in the last
+            // millisecond so no earlier ID is repeated.
+            timestamp = *last_timestamp;
             self.sequence = (self.sequence + 1) & (-1 ^ (-1 << 12));
-            if self.sequence == 0 && timestamp <= *last_timestamp {
+            // The sequence ran out for this millisecond, so wait for the next one.
+            while self.sequence == 0 && timestamp <= *last_timestamp {
                 timestamp = self.get_time();
             }
         } else {
             self.sequence = 0;
         }
+        if timestamp >> TIMESTAMP_BITS != 0 {
+            panic!("Snowflake timestamp `{}` overflows {} bits", timestamp, TIMESTAMP_BITS);
+        }
         *last_timestamp = timestamp;
         (timestamp << 22) | (self.worker_id << 17) | (self.datacenter_id << 12) | self.sequence
     }
 
+    /// Get the generator's settings, e.g. to check that every node has its own worker and
+    /// datacenter IDs.
+    pub fn config(&self) -> SnowflakeConfig {
+        SnowflakeConfig {
+            epoch: self.epoch,
+            worker_id: self.worker_id,
+            datacenter_id: self.datacenter_id,
+            layout: LAYOUT,
+        }
+    }
+
+    /// Split an ID made by this generator into its fields.
+    pub fn decode(&self, id: i64) -> SnowflakeParts {
+        decode_with_epoch(id, self.epoch)
+    }
+
     pub fn generate_u8_u64(&mut self) -> [u8; 8] {
         self.generate().to_le_bytes()
     }
 
-    fn get_time(&self) -> i64 {
-        Utc::now().timestamp_millis() - self.epoch
+    fn get_time(&mut self) -> i64 {
+        let now = match &mut self.clock {
+            Clock::System => Utc::now().timestamp_millis(),
+            Clock::Deterministic(now) => {
+                *now += 1;
+                *now - 1
+            }
+        };
+        now - self.epoch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let mut a = Snowflake::deterministic(1_600_000_000_000);
+        let mut b = Snowflake::deterministic(1_600_000_000_000);
+
+        let a: Vec<i64> = (0..100).map(|_| a.generate()).collect();
+        let b: Vec<i64> = (0..100).map(|_| b.generate()).collect();
+
+        assert_eq!(a, b);
+        assert!(a.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn new() {
+        let epoch = Snowflake::default().epoch;
+        let a = Snowflake::new(epoch, 3, 1).unwrap().generate();
+        let b = Snowflake::new(epoch, 4, 1).unwrap().generate();
+        assert_eq!((a >> 17) & 0x1f, 3);
+        assert_eq!((b >> 17) & 0x1f, 4);
+
+        assert_eq!(Snowflake::new(epoch, 32, 1).err(), Some(SnowflakeError::WorkerIdOutOfRange(32)));
+        assert_eq!(
+            Snowflake::new(epoch, 1, -1).err(),
+            Some(SnowflakeError::DatacenterIdOutOfRange(-1))
+        );
+    }
+
+    #[test]
+    fn decode() {
+        let mut snowflake = Snowflake::new(Snowflake::default().epoch, 5, 9).unwrap();
+        let ids = snowflake.reserve(2);
+        let first = snowflake.decode(ids[0]);
+        let second = snowflake.decode(ids[1]);
+
+        assert_eq!(first.worker_id, 5);
+        assert_eq!(first.datacenter_id, 9);
+        assert!(first.timestamp <= second.timestamp);
+        assert!((first.timestamp, first.sequence) < (second.timestamp, second.sequence));
+
+        let start = 1_600_000_000_000;
+        let mut snowflake = Snowflake::deterministic(start);
+        let id = snowflake.generate();
+        let parts = snowflake.decode(id);
+        assert_eq!(parts.timestamp.timestamp_millis(), start);
+        assert_eq!(parts.sequence, 0);
+    }
+
+    #[test]
+    fn default_epoch_is_millis() {
+        let mut snowflake = Snowflake::default();
+        let id = snowflake.generate();
+        let age = Utc::now() - snowflake.decode(id).timestamp;
+        assert!(age.num_milliseconds().abs() < 1000);
+    }
+
+    #[test]
+    fn config() {
+        let config = Snowflake::new(1_600_000_000_000, 7, 12).unwrap().config();
+        assert_eq!(config.epoch, 1_600_000_000_000);
+        assert_eq!(config.worker_id, 7);
+        assert_eq!(config.datacenter_id, 12);
+        assert_eq!(config.layout, LAYOUT);
+    }
+
+    #[test]
+    fn reserve() {
+        let ids = Snowflake::default().reserve(1000);
+        assert_eq!(ids.len(), 1000);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+
+        let ids = Snowflake::default().reserve(10_000);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn concurrent_generate() {
+        let snowflake = Arc::new(Mutex::new(Snowflake::default()));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let snowflake = snowflake.clone();
+                std::thread::spawn(move || {
+                    (0..10_000).map(|_| snowflake.lock().generate()).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let ids: std::collections::HashSet<i64> =
+            threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+        assert_eq!(ids.len(), 80_000);
+    }
+
+    #[test]
+    fn clock_stepping_back() {
+        let mut snowflake = Snowflake::deterministic(1_600_000_000_000);
+        let first = snowflake.generate();
+        snowflake.clock = Clock::Deterministic(1_600_000_000_000 - 5);
+        let second = snowflake.generate();
+        assert!(second > first);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows 41 bits")]
+    fn timestamp_overflow() {
+        let mut snowflake = Snowflake::deterministic(Snowflake::default().epoch + (1 << 41) - 1);
+        snowflake.generate();
+        snowflake.generate();
     }
 }
@@ -0,0 +1,237 @@
+use super::{packet::Packet, wire};
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_stream::Stream;
+
+/// How many malformed frames [`PacketStream::read_valid_packet`] skips in a row by default.
+const DEFAULT_SKIP_LIMIT: usize = 8;
+
+/// Reads and writes whole 1024 byte packets over a byte stream.
+pub struct PacketStream<S> {
+    inner: S,
+    /// The frame currently being read, kept across calls so partial reads are never lost.
+    buf: Box<[u8; 1024]>,
+    filled: usize,
+    skip_limit: usize,
+    checksums: bool,
+}
+
+impl<S> PacketStream<S> {
+    pub fn new(inner: S) -> Self {
+        PacketStream {
+            inner,
+            buf: Box::new([0; 1024]),
+            filled: 0,
+            skip_limit: DEFAULT_SKIP_LIMIT,
+            checksums: false,
+        }
+    }
+
+    /// Stamp a checksum on every packet written, and fail reads of packets whose checksum doesn't
+    /// match with `InvalidData`.
+    pub fn with_checksums(mut self, on: bool) -> Self {
+        self.checksums = on;
+        self
+    }
+
+    /// Set how many malformed frames in a row [`PacketStream::read_valid_packet`] skips before
+    /// giving up.
+    pub fn with_skip_limit(mut self, limit: usize) -> Self {
+        self.skip_limit = limit;
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Get the underlying stream. Any partially read frame is discarded.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> PacketStream<S> {
+    /// Poll for the next packet.
+    pub fn poll_read_packet(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Packet>> {
+        while self.filled < self.buf.len() {
+            let mut buf = ReadBuf::new(&mut self.buf[self.filled..]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut buf))?;
+
+            let n = buf.filled().len();
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.filled += n;
+        }
+
+        self.filled = 0;
+        let packet = Packet::new(*self.buf);
+        if self.checksums && !packet.verify_checksum() {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "Bad packet checksum.");
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(packet))
+    }
+
+    /// Read the next packet.
+    ///
+    /// This is cancellation safe. If the future is dropped before the whole frame has arrived, the
+    /// bytes read so far stay buffered and the next call picks up where this one left off.
+    pub async fn read_packet(&mut self) -> io::Result<Packet> {
+        poll_fn(|cx| self.poll_read_packet(cx)).await
+    }
+
+    /// Read the next packet that passes [`Packet::validate`], skipping malformed frames.
+    ///
+    /// Fails with `InvalidData` once more than the skip limit of frames in a row are malformed.
+    /// This is cancellation safe, though frames skipped before cancellation stay skipped.
+    pub async fn read_valid_packet(&mut self) -> io::Result<Packet> {
+        let mut skipped = 0;
+        loop {
+            let packet = self.read_packet().await?;
+            match packet.validate() {
+                Ok(()) => return Ok(packet),
+                Err(e) if skipped >= self.skip_limit => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+                }
+                Err(_) => skipped += 1,
+            }
+        }
+    }
+}
+
+/// Yields packets until EOF. EOF at a frame boundary ends the stream cleanly, while EOF in the middle
+/// of a frame yields an `UnexpectedEof` error first.
+impl<S: AsyncRead + Unpin> Stream for PacketStream<S> {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        match ready!(this.poll_read_packet(cx)) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && this.filled == 0 => Poll::Ready(None),
+            Err(e) => {
+                this.filled = 0;
+                Poll::Ready(Some(Err(e)))
+            }
+            Ok(packet) => Poll::Ready(Some(Ok(packet))),
+        }
+    }
+}
+
+/// Read packets from `reader` until EOF, e.g. from a recorded packet log or a pipe.
+pub fn packet_stream<R: AsyncRead + Unpin>(reader: R) -> impl Stream<Item = io::Result<Packet>> {
+    PacketStream::new(reader)
+}
+
+impl<S: AsyncWrite + Unpin> PacketStream<S> {
+    /// Write a packet and flush it.
+    ///
+    /// This is not cancellation safe: dropping the future may leave a partial frame on the wire.
+    pub async fn write_packet(&mut self, packet: &Packet) -> io::Result<()> {
+        if self.checksums {
+            let mut packet = Packet::new(packet.0);
+            packet.set_checksum();
+            return wire::write_packet(&mut self.inner, &packet).await;
+        }
+        wire::write_packet(&mut self.inner, packet).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base_types::auth::SessionId;
+    use std::{io::Cursor, time::Duration};
+    use tokio::{io::AsyncWriteExt, time::timeout};
+    use tokio_stream::StreamExt;
+
+    #[tokio::test]
+    async fn cancelled_read() {
+        let (mut tx, rx) = tokio::io::duplex(2048);
+        let mut stream = PacketStream::new(rx);
+
+        let mut frame = [0; 1024];
+        frame.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+
+        tx.write_all(&frame[..500]).await.unwrap();
+        assert!(timeout(Duration::from_millis(20), stream.read_packet()).await.is_err());
+
+        tx.write_all(&frame[500..]).await.unwrap();
+        let packet = stream.read_packet().await.unwrap();
+        assert_eq!(packet.0, frame);
+    }
+
+    #[tokio::test]
+    async fn truncated_frame() {
+        let (mut tx, rx) = tokio::io::duplex(2048);
+        let mut stream = PacketStream::new(rx);
+
+        tx.write_all(&[1; 100]).await.unwrap();
+        drop(tx);
+
+        let err = stream.read_packet().await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn skip_malformed() {
+        let (mut tx, rx) = tokio::io::duplex(4096);
+        let mut stream = PacketStream::new(rx).with_skip_limit(1);
+
+        let valid = Packet::login_ok(SessionId(9));
+        tx.write_all(&[0xff; 1024]).await.unwrap();
+        tx.write_all(&valid.0).await.unwrap();
+        assert_eq!(stream.read_valid_packet().await.unwrap().0, valid.0);
+
+        tx.write_all(&[0xff; 1024]).await.unwrap();
+        tx.write_all(&[0xff; 1024]).await.unwrap();
+        let err = stream.read_valid_packet().await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn stream_until_eof() {
+        let log: Vec<u8> = (1..=3).flat_map(|i| Packet::login_ok(SessionId(i)).0).collect();
+
+        let read: Vec<Packet> = packet_stream(Cursor::new(log.clone()))
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let sessions: Vec<_> = read.iter().map(|p| p.login_ok_session().unwrap()).collect();
+        assert_eq!(sessions, [SessionId(1), SessionId(2), SessionId(3)]);
+
+        let mut stream = packet_stream(Cursor::new(log[..1500].to_vec()));
+        assert!(stream.next().await.unwrap().is_ok());
+        let err = stream.next().await.unwrap().err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn checksums() {
+        let (tx, rx) = tokio::io::duplex(4096);
+        let mut tx = PacketStream::new(tx).with_checksums(true);
+        let mut rx = PacketStream::new(rx).with_checksums(true);
+
+        tx.write_packet(&Packet::login_ok(SessionId(4))).await.unwrap();
+        let packet = rx.read_packet().await.unwrap();
+        assert_eq!(packet.login_ok_session(), Some(SessionId(4)));
+
+        let mut corrupt = Packet::login_ok(SessionId(4));
+        corrupt.set_checksum();
+        corrupt.0[3] ^= 1;
+        tx.get_mut().write_all(&corrupt.0).await.unwrap();
+        let err = rx.read_packet().await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,54 @@
+use super::packet::Packet;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Read one whole packet.
+///
+/// Returns `None` if the peer closed the connection cleanly between packets, and fails with
+/// `UnexpectedEof` if it closed partway through one. This is not cancellation safe; use
+/// [`PacketStream`](super::stream::PacketStream) where reads may be cancelled.
+pub async fn read_packet<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Option<Packet>> {
+    let mut buf = [0; 1024];
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = r.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        filled += n;
+    }
+    Ok(Some(Packet::new(buf)))
+}
+
+/// Write one whole packet and flush it.
+pub async fn write_packet<W: AsyncWrite + Unpin>(w: &mut W, p: &Packet) -> io::Result<()> {
+    w.write_all(&p.0).await?;
+    w.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base_types::auth::SessionId;
+
+    #[tokio::test]
+    async fn clean_and_truncated_close() {
+        let (mut tx, mut rx) = tokio::io::duplex(4096);
+        write_packet(&mut tx, &Packet::login_ok(SessionId(5))).await.unwrap();
+        drop(tx);
+
+        let packet = read_packet(&mut rx).await.unwrap().unwrap();
+        assert_eq!(packet.login_ok_session(), Some(SessionId(5)));
+        assert!(read_packet(&mut rx).await.unwrap().is_none());
+
+        let (mut tx, mut rx) = tokio::io::duplex(4096);
+        tx.write_all(&[1; 100]).await.unwrap();
+        drop(tx);
+
+        let err = read_packet(&mut rx).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,381 @@
+use std::{
+    collections::VecDeque,
+    io::{Error, ErrorKind, Result},
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{tcp::OwnedReadHalf, TcpStream, ToSocketAddrs},
+    sync::{mpsc, oneshot},
+};
+use tokio_stream::Stream;
+use crate::base_types::{
+    packet::{Opcode, Packet},
+    stream::PacketStream,
+    wire,
+};
+
+/// Set in the nonce of every keepalive ping, so their replies can be told apart from
+/// [`Client::ping`]'s.
+const KEEPALIVE_NONCE: u64 = 1 << 63;
+
+/// A packet for the writer task, and where to report whether writing it worked.
+type Outgoing = (Packet, Option<oneshot::Sender<Result<()>>>);
+
+pub struct Client {
+    reader: PacketStream<OwnedReadHalf>,
+    /// Every write goes through the writer task, so packets from `send`, subscriptions and the
+    /// keepalive never interleave mid-frame.
+    writer: mpsc::UnboundedSender<Outgoing>,
+    /// Packets that arrived while waiting for something else, in arrival order.
+    pending: VecDeque<Packet>,
+    /// The nonce for the next [`Client::ping`].
+    next_nonce: u64,
+    /// Resolves with the error that stopped the keepalive task, reported by the next or pending
+    /// `send` or `recv`. Dropping it stops the keepalive task.
+    keepalive_failed: Option<oneshot::Receiver<Error>>,
+}
+
+impl Client {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let (reader, mut socket) = TcpStream::connect(addr).await?.into_split();
+        let (writer, mut outgoing) = mpsc::unbounded_channel::<Outgoing>();
+        tokio::spawn(async move {
+            while let Some((packet, done)) = outgoing.recv().await {
+                let written = wire::write_packet(&mut socket, &packet).await;
+                let failed = written.is_err();
+                if let Some(done) = done {
+                    let _ = done.send(written);
+                }
+                if failed {
+                    break;
+                }
+            }
+        });
+
+        Ok(Client {
+            reader: PacketStream::new(reader),
+            writer,
+            pending: VecDeque::new(),
+            next_nonce: 0,
+            keepalive_failed: None,
+        })
+    }
+
+    /// Send a packet and flush it.
+    pub async fn send(&mut self, packet: Packet) -> Result<()> {
+        self.check_keepalive()?;
+        write(&self.writer, packet).await
+    }
+
+    /// Ping the server every `interval` in the background to keep the connection alive, replacing
+    /// any earlier keepalive. The replies are consumed, so [`Client::recv`] never sees them.
+    ///
+    /// If a ping can't be written the keepalive stops, and the next `send`, or a `recv` that is
+    /// already waiting, fails with its error.
+    pub fn spawn_keepalive(&mut self, interval: Duration) {
+        let (mut failed, failed_rx) = oneshot::channel();
+        self.keepalive_failed = Some(failed_rx);
+        let writer = self.writer.clone();
+        tokio::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticks = tokio::time::interval_at(start, interval);
+            for n in 0.. {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    // The client was dropped or started another keepalive.
+                    _ = failed.closed() => return,
+                }
+                let ping = Packet::ping_with_nonce(0, KEEPALIVE_NONCE | n);
+                if let Err(e) = write(&writer, ping).await {
+                    let _ = failed.send(e);
+                    return;
+                }
+            }
+        });
+    }
+
+    fn check_keepalive(&mut self) -> Result<()> {
+        match self.keepalive_failed.as_mut().map(|failed| failed.try_recv()) {
+            Some(Ok(e)) => {
+                self.keepalive_failed = None;
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the next packet, failing early if the keepalive fails while waiting.
+    async fn read(&mut self) -> Result<Packet> {
+        self.check_keepalive()?;
+        let failed = keepalive_failure(&mut self.keepalive_failed);
+        tokio::select! {
+            packet = self.reader.read_packet() => packet,
+            e = failed => Err(e),
+        }
+    }
+
+    /// Receive the next packet. Fails with `InvalidData` if it has a newer protocol version or an
+    /// unknown opcode.
+    pub async fn recv(&mut self) -> Result<Packet> {
+        loop {
+            let packet = match self.pending.pop_front() {
+                Some(packet) => packet,
+                None => self.read().await?,
+            };
+            if is_keepalive_reply(&packet) {
+                continue;
+            }
+            packet
+                .validate()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            return Ok(packet);
+        }
+    }
+
+    /// Ping the server and wait for its `Ok`, returning the round-trip time.
+    ///
+    /// Each ping carries its own nonce, so only the matching reply ends the wait. Anything else
+    /// that arrives meanwhile is kept for [`Client::recv`].
+    pub async fn ping(&mut self) -> Result<Duration> {
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+
+        let start = Instant::now();
+        self.send(Packet::ping_with_nonce(0, nonce)).await?;
+        loop {
+            let packet = self.read().await?;
+            if packet.try_op().ok() == Some(Opcode::Ok) && packet.ping_nonce() == Some(nonce) {
+                return Ok(start.elapsed());
+            }
+            self.pending.push_back(packet);
+        }
+    }
+
+    /// Stream only the packets whose opcode is in `ops`.
+    ///
+    /// Pings are answered while the stream is polled, whether or not they are in `ops`, and all
+    /// other packets are dropped. The stream ends when the connection closes or fails, or when a
+    /// packet arrives that [`Client::recv`] would reject.
+    pub fn subscribe(&mut self, ops: &[Opcode]) -> Subscription<'_> {
+        Subscription {
+            client: self,
+            ops: ops.to_vec(),
+        }
+    }
+}
+
+/// Queue `packet` for the writer task and wait until it has been written.
+async fn write(writer: &mpsc::UnboundedSender<Outgoing>, packet: Packet) -> Result<()> {
+    let closed = || Error::from(ErrorKind::BrokenPipe);
+    let (done, written) = oneshot::channel();
+    writer.send((packet, Some(done))).map_err(|_| closed())?;
+    written.await.map_err(|_| closed())?
+}
+
+/// Resolve with the keepalive's error once it fails, and never if there is no keepalive or it stops
+/// without failing.
+async fn keepalive_failure(failed: &mut Option<oneshot::Receiver<Error>>) -> Error {
+    if let Some(receiver) = failed {
+        if let Ok(e) = receiver.await {
+            *failed = None;
+            return e;
+        }
+        *failed = None;
+    }
+    std::future::pending().await
+}
+
+fn is_keepalive_reply(packet: &Packet) -> bool {
+    packet.try_op().ok() == Some(Opcode::Ok)
+        && packet.ping_nonce().is_some_and(|nonce| nonce & KEEPALIVE_NONCE != 0)
+}
+
+/// A stream of the packets a [`Client`] subscribed to, see [`Client::subscribe`].
+pub struct Subscription<'a> {
+    client: &'a mut Client,
+    ops: Vec<Opcode>,
+}
+
+impl Stream for Subscription<'_> {
+    type Item = Packet;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Packet>> {
+        let this = self.get_mut();
+        loop {
+            let packet = match this.client.pending.pop_front() {
+                Some(packet) => packet,
+                None => match ready!(this.client.reader.poll_read_packet(cx)) {
+                    Ok(packet) => packet,
+                    Err(_) => return Poll::Ready(None),
+                },
+            };
+            if is_keepalive_reply(&packet) {
+                continue;
+            }
+            if packet.validate().is_err() {
+                return Poll::Ready(None);
+            }
+            let pong = match packet.try_op() {
+                Ok(op) if this.ops.contains(&op) => return Poll::Ready(Some(packet)),
+                Ok(Opcode::Ping) => Packet::ping_reply(&packet),
+                _ => continue,
+            };
+            if this.client.writer.send((pong, None)).is_err() {
+                return Poll::Ready(None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        base_types::{
+            auth::SessionId,
+            packet::{Opcode, PROTOCOL_VERSION},
+        },
+        server::Server,
+    };
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_stream::StreamExt;
+
+    /// Connect a client to a bare socket standing in for the server.
+    async fn connect_to_peer() -> (Client, PacketStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        (client, PacketStream::new(socket))
+    }
+
+    #[tokio::test]
+    async fn ping_server() {
+        let server = Arc::new(Server::new("127.0.0.1:0").await.unwrap());
+        let addr = server.local_addr().await.unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+
+        let mut client = Client::connect(addr).await.unwrap();
+        client.send(Packet::ping_with_nonce(1, 7)).await.unwrap();
+        let reply = client.recv().await.unwrap();
+        assert_eq!(reply.op(), Opcode::Ok);
+        assert_eq!(reply.ping_nonce(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn ping() {
+        let (mut client, mut peer) = connect_to_peer().await;
+        tokio::spawn(async move {
+            let ping = peer.read_packet().await.unwrap();
+            // An unrelated packet and a stale reply arrive before the matching one.
+            peer.write_packet(&Packet::message(1, 2, SessionId(3), "hi").unwrap()).await.unwrap();
+            peer.write_packet(&Packet::ping_reply(&Packet::ping_with_nonce(0, 99))).await.unwrap();
+            peer.write_packet(&Packet::ping_reply(&ping)).await.unwrap();
+        });
+
+        let rtt = client.ping().await.unwrap();
+        assert!(rtt > Duration::ZERO);
+        assert!(rtt < Duration::from_secs(1));
+
+        assert_eq!(client.recv().await.unwrap().message_content().unwrap(), "hi");
+        assert_eq!(client.recv().await.unwrap().ping_nonce(), Some(99));
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let (mut client, mut peer) = connect_to_peer().await;
+        tokio::spawn(async move {
+            // Answer two keepalive pings, then send something the client should see.
+            for _ in 0..2 {
+                let ping = peer.read_packet().await.unwrap();
+                assert_eq!(ping.op(), Opcode::Ping);
+                peer.write_packet(&Packet::ping_reply(&ping)).await.unwrap();
+            }
+            peer.write_packet(&Packet::message(1, 2, SessionId(3), "hi").unwrap()).await.unwrap();
+        });
+
+        client.spawn_keepalive(Duration::from_millis(20));
+        let packet = client.recv().await.unwrap();
+        assert_eq!(packet.message_content().unwrap(), "hi");
+    }
+
+    #[tokio::test]
+    async fn keepalive_error() {
+        // The peer stays connected and silent, so only the keepalive can end a pending `recv`.
+        let (mut client, _peer) = connect_to_peer().await;
+
+        // Writes fail as if the writer task had died, while the read side stays open.
+        client.writer = mpsc::unbounded_channel().0;
+        client.spawn_keepalive(Duration::from_millis(5));
+        let received = tokio::time::timeout(Duration::from_secs(1), client.recv()).await;
+        assert_eq!(received.unwrap().unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let (mut client, mut peer) = connect_to_peer().await;
+        let answered = tokio::spawn(async move {
+            peer.write_packet(&Packet::ping_with_nonce(1, 99)).await.unwrap();
+            peer.write_packet(&Packet::ping_reply(&Packet::ping_with_nonce(1, 5))).await.unwrap();
+            peer.write_packet(&Packet::message(1, 2, SessionId(3), "hi").unwrap()).await.unwrap();
+            peer.read_packet().await.unwrap()
+        });
+
+        let mut messages = client.subscribe(&[Opcode::Message]);
+        let packet = messages.next().await.unwrap();
+        assert_eq!(packet.op(), Opcode::Message);
+        assert_eq!(packet.message_content().unwrap(), "hi");
+
+        let pong = answered.await.unwrap();
+        assert_eq!(pong.op(), Opcode::Ok);
+        assert_eq!(pong.ping_nonce(), Some(99));
+    }
+
+    #[tokio::test]
+    async fn subscribe_invalid() {
+        let (mut client, mut peer) = connect_to_peer().await;
+        tokio::spawn(async move {
+            let mut stale = Packet::message(1, 2, SessionId(3), "from the future").unwrap();
+            stale.set_version(255);
+            peer.write_packet(&stale).await.unwrap();
+            peer.write_packet(&Packet::message(1, 2, SessionId(3), "hi").unwrap()).await.unwrap();
+            peer.read_packet().await
+        });
+
+        // The stream ends at the packet `recv` would fail on, rather than yielding it.
+        let mut messages = client.subscribe(&[Opcode::Message]);
+        assert!(messages.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn message_roundtrip() {
+        let (mut client, mut peer) = connect_to_peer().await;
+        tokio::spawn(async move {
+            // Echo every packet back.
+            while let Ok(packet) = peer.read_packet().await {
+                peer.write_packet(&packet).await.unwrap();
+            }
+        });
+
+        let mut message = Packet::new([0; 1024]);
+        message.reset(Opcode::Message);
+        message.set_message_ids(1, 2, 3).unwrap();
+        message.set_content("hello".parse().unwrap()).unwrap();
+        client.send(message).await.unwrap();
+
+        let echoed = client.recv().await.unwrap();
+        assert_eq!(echoed.message_content().unwrap(), "hello");
+        assert_eq!(echoed.session_id(), Some(SessionId(3)));
+
+        let mut stale = Packet::login_ok(SessionId(1));
+        stale.set_version(PROTOCOL_VERSION + 1);
+        client.send(stale).await.unwrap();
+        let err = client.recv().await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
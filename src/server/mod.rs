@@ -1,26 +1,892 @@
-use std::{io::Result, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Result,
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
+    io::AsyncRead,
     net::{TcpListener, ToSocketAddrs},
-    sync::Mutex,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        watch, Mutex,
+    },
+};
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use self::rate_limit::TokenBucket;
+use crate::base_types::{
+    auth::{SessionId, UserToken},
+    packet::{ErrorCode, Opcode, Packet, SNOWFLAKE},
+    wire,
 };
-use crate::base_types::auth::SessionId;
+use chrono::Utc;
 
-pub struct Server {
-    listener: Arc<Mutex<TcpListener>>,
+mod rate_limit;
+
+/// A point-in-time view of a connected session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: SessionId,
+}
+
+/// Why a logged in session went away, see [`Server::disconnects`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client said `Goodbye`.
+    ClientClosed,
+    /// The client sent nothing within the ping timeout.
+    TimedOut,
+    /// The client sent a frame that failed validation.
+    ProtocolError,
+    /// The connection closed or failed without a `Goodbye`.
+    ConnectionLost,
+    /// The server shut down.
+    ServerShutdown,
+}
+
+/// The connected sessions, shared with every connection task.
+#[derive(Default)]
+struct Registry {
     /// User info caching can be done at webservers
     users: Vec<SessionId>,
+    /// The session each logged in token holds.
+    tokens: HashMap<UserToken, SessionId>,
+    /// Where to queue packets for each logged in connection.
+    senders: HashMap<SessionId, mpsc::Sender<Packet>>,
+    /// Where to forward packets the server does not answer itself, see [`Server::incoming`].
+    inbound: Option<mpsc::Sender<(SessionId, Packet)>>,
+    /// Where to report sessions that went away, see [`Server::disconnects`].
+    disconnects: Option<mpsc::Sender<(SessionId, DisconnectReason)>>,
+}
+
+/// How many packets may be queued for a connection that isn't keeping up with its writes.
+const OUTBOUND_CAPACITY: usize = 64;
+
+/// How many packets may wait for the [`Server::incoming`] stream to take them, and how many
+/// disconnects for the [`Server::disconnects`] stream.
+const INBOUND_CAPACITY: usize = 256;
+
+/// How long [`Server::shutdown`] waits by default for queued packets to be written.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long [`Server::run`] waits after a failed accept before trying again.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Per connection limits, copied into every connection task.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    /// How long a connection may go without sending a packet before it is dropped.
+    ping_timeout: Option<Duration>,
+    /// How many packets a connection may send, and per how long.
+    rate_limit: Option<(u32, Duration)>,
+}
+
+pub struct Server {
+    /// `None` once the server has shut down. [`Server::run`] holds the lock while it accepts.
+    listener: Arc<Mutex<Option<TcpListener>>>,
+    /// Kept apart from the listener, so it can be read while the server runs.
+    local_addr: SocketAddr,
+    registry: Arc<parking_lot::Mutex<Registry>>,
+    limits: Limits,
+    /// How long a connection may take to write its queued packets once shutdown begins.
+    shutdown_grace: Duration,
+    /// Set to `true` when shutdown begins.
+    shutdown_tx: watch::Sender<bool>,
+    shutdown_rx: watch::Receiver<bool>,
+    /// Every connection task holds a clone of this sender, so once it is taken the receiver closes
+    /// when the last connection is done.
+    drain_tx: parking_lot::Mutex<Option<mpsc::Sender<()>>>,
+    drain_rx: Mutex<mpsc::Receiver<()>>,
 }
 
 impl Server {
     pub async fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Server::with_listener(TcpListener::bind(addr).await?)
+    }
+
+    /// Adopt an already bound listener, e.g. one passed in through systemd socket activation.
+    ///
+    /// The listener must be in non-blocking mode.
+    pub fn from_std(listener: std::net::TcpListener) -> Result<Self> {
+        Server::with_listener(TcpListener::from_std(listener)?)
+    }
+
+    fn with_listener(listener: TcpListener) -> Result<Self> {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (drain_tx, drain_rx) = mpsc::channel(1);
         Ok(Server {
-            listener: Arc::new(Mutex::new(TcpListener::bind(addr).await?)),
-            users: Vec::new(),
+            local_addr: listener.local_addr()?,
+            listener: Arc::new(Mutex::new(Some(listener))),
+            registry: Default::default(),
+            limits: Limits::default(),
+            shutdown_grace: SHUTDOWN_GRACE,
+            shutdown_tx,
+            shutdown_rx,
+            drain_tx: parking_lot::Mutex::new(Some(drain_tx)),
+            drain_rx: Mutex::new(drain_rx),
         })
     }
 
+    /// Drop connections that send nothing for `timeout`, removing their session. Any packet counts,
+    /// so a client only needs to `Ping` while it has nothing else to send.
+    pub fn with_ping_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.ping_timeout = Some(timeout);
+        self
+    }
+
+    /// Let each connection, and so each session, send at most `max_packets` every `per`. The budget
+    /// refills continuously, so bursts are smoothed rather than reset at window boundaries.
+    ///
+    /// A packet over the budget is dropped and answered with an `Error` carrying `RateLimited`.
+    /// `Health` probes are never limited.
+    pub fn with_rate_limit(mut self, max_packets: u32, per: Duration) -> Self {
+        self.limits.rate_limit = Some((max_packets, per));
+        self
+    }
+
+    /// Give connections `grace` to write the packets queued for them once shutdown begins, 5
+    /// seconds by default. Connections still writing after that, e.g. to a peer that stopped
+    /// reading, are closed without the rest.
+    pub fn with_shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Get the address the server is listening on. Fails with `NotConnected` once shutdown begins.
+    pub async fn local_addr(&self) -> Result<SocketAddr> {
+        if *self.shutdown_rx.borrow() {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        }
+        Ok(self.local_addr)
+    }
+
+    /// Accept connections until [`Server::shutdown`] is called, handling each one on its own task.
+    ///
+    /// A failed accept, e.g. a connection aborted before it was accepted or running out of file
+    /// descriptors, is retried after a short pause rather than stopping the server. A connection
+    /// that closes, even in the middle of a packet, only ends its own task.
+    pub async fn run(&self) -> Result<()> {
+        let mut guard = self.listener.lock().await;
+        let listener = match &*guard {
+            Some(listener) => listener,
+            None => return Ok(()),
+        };
+        let mut shutdown = self.shutdown_rx.clone();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.changed() => break,
+            };
+            let socket = match accepted {
+                Ok((socket, _)) => socket,
+                Err(_) => {
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            let drain = match self.drain_tx.lock().clone() {
+                Some(drain) => drain,
+                None => break,
+            };
+            let (mut reader, mut writer) = socket.into_split();
+            let (tx, mut rx) = mpsc::channel(OUTBOUND_CAPACITY);
+            // Every write to the connection goes through the channel, so replies and broadcasts
+            // never interleave mid-frame. The writer stops once every sender is gone. The channel
+            // is bounded, so a peer that stops reading can't make the server buffer without end.
+            let mut written = tokio::spawn(async move {
+                while let Some(packet) = rx.recv().await {
+                    if wire::write_packet(&mut writer, &packet).await.is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let registry = self.registry.clone();
+            let limits = self.limits;
+            let grace = self.shutdown_grace;
+            let mut shutdown = self.shutdown_rx.clone();
+            tokio::spawn(async move {
+                let _drain = drain;
+                let mut session = None;
+                let handled =
+                    handle_connection(&mut reader, tx, &registry, &mut session, limits);
+                let reason = tokio::select! {
+                    handled = handled => match handled {
+                        Ok(reason) => reason,
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                            DisconnectReason::TimedOut
+                        }
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                            DisconnectReason::ProtocolError
+                        }
+                        Err(_) => DisconnectReason::ConnectionLost,
+                    },
+                    _ = shutdown.changed() => DisconnectReason::ServerShutdown,
+                };
+                if let Some(id) = session {
+                    leave(&registry, id, reason);
+                }
+                let deadline = async {
+                    shutting_down(&mut shutdown).await;
+                    tokio::time::sleep(grace).await
+                };
+                tokio::select! {
+                    _ = &mut written => {}
+                    _ = deadline => written.abort(),
+                }
+            });
+        }
+        // Dropping the listener refuses any further connections.
+        *guard = None;
+        Ok(())
+    }
+
+    /// Stop accepting connections, then close every connection once the packets queued for it are
+    /// written, and wait for that to finish. A connection gets at most the grace period set with
+    /// [`Server::with_shutdown_grace`] to do so. [`Server::run`] returns once shutdown begins.
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(true);
+        self.drain_tx.lock().take();
+        self.listener.lock().await.take();
+        self.drain_rx.lock().await.recv().await;
+    }
+
+    /// Register a session. Registering a session twice has no effect.
+    pub fn add_session(&self, id: SessionId) {
+        let mut registry = self.registry.lock();
+        if !registry.users.contains(&id) {
+            registry.users.push(id);
+        }
+    }
+
+    /// Remove a session, freeing the token it logged in with.
+    pub fn remove_session(&self, id: &SessionId) {
+        remove_session(&self.registry, id);
+    }
+
+    /// Register many sessions at once. Sessions that are already registered, or repeated in `ids`,
+    /// are only registered once, as with [`Server::add_session`].
+    pub fn register_sessions(&self, ids: impl IntoIterator<Item = SessionId>) {
+        let mut registry = self.registry.lock();
+        let mut seen: HashSet<SessionId> = registry.users.iter().copied().collect();
+        registry.users.extend(ids.into_iter().filter(|id| seen.insert(*id)));
+    }
+
+    /// Remove many sessions at once.
+    pub fn unregister_sessions(&self, ids: impl IntoIterator<Item = SessionId>) {
+        let ids: HashSet<SessionId> = ids.into_iter().collect();
+        let mut registry = self.registry.lock();
+        registry.users.retain(|id| !ids.contains(id));
+        registry.tokens.retain(|_, id| !ids.contains(id));
+        registry.senders.retain(|id, _| !ids.contains(id));
+    }
+
+    /// Send `packet` to every logged in connection. Connections that have gone away are skipped and
+    /// forgotten, and connections whose write queue is full miss this packet.
+    pub fn broadcast(&self, packet: Packet) {
+        broadcast(&self.registry, packet);
+    }
+
+    /// Get the number of registered sessions.
+    pub fn session_count(&self) -> usize {
+        self.registry.lock().users.len()
+    }
+
+    /// Stream the currently connected sessions.
+    ///
+    /// The sessions are snapshotted when this is called, so sessions joining or leaving while the
+    /// stream is consumed are not reflected in it.
+    pub fn sessions_stream(&self) -> impl Stream<Item = SessionInfo> {
+        let snapshot: Vec<SessionInfo> =
+            self.registry.lock().users.iter().map(|&id| SessionInfo { id }).collect();
+        tokio_stream::iter(snapshot)
+    }
+
+    /// Stream the packets logged in sessions send that the server does not answer itself, e.g.
+    /// `Message`s, for custom dispatch.
+    ///
+    /// Only the most recent stream receives packets; calling this again ends the previous one. A
+    /// connection that sends a malformed frame is dropped without ending the stream.
+    ///
+    /// At most 256 packets wait for the stream. While it is full, a connection with a packet for
+    /// it stops reading until there is room, so a slow consumer holds back its senders rather than
+    /// losing their packets or letting them queue without bound.
+    pub fn incoming(&self) -> impl Stream<Item = (SessionId, Packet)> {
+        let (tx, rx) = mpsc::channel(INBOUND_CAPACITY);
+        self.registry.lock().inbound = Some(tx);
+        ReceiverStream::new(rx)
+    }
+
+    /// Stream the logged in sessions that go away, and why.
+    ///
+    /// Only the most recent stream receives disconnects; calling this again ends the previous one.
+    /// Disconnects that find the stream holding 256 unread ones already are dropped, so a stream
+    /// nobody reads never holds a connection up.
+    pub fn disconnects(&self) -> impl Stream<Item = (SessionId, DisconnectReason)> {
+        let (tx, rx) = mpsc::channel(INBOUND_CAPACITY);
+        self.registry.lock().disconnects = Some(tx);
+        ReceiverStream::new(rx)
+    }
+
+    /// Shut the server down and forget every session.
     pub async fn destroy(&mut self) {
-        drop(self.listener.lock().await);
-        self.users.clear();
+        self.shutdown().await;
+        *self.registry.lock() = Default::default();
+    }
+}
+
+/// Resolve once shutdown has begun, even if the change was already seen.
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // The server is gone without shutting down, so nothing waits on this connection.
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+fn broadcast(registry: &parking_lot::Mutex<Registry>, packet: Packet) {
+    registry.lock().senders.retain(|_, sender| {
+        !matches!(sender.try_send(Packet::new(packet.0)), Err(TrySendError::Closed(_)))
+    });
+}
+
+/// Remove the session `id` once its connection is done, and tell everyone else it left.
+///
+/// The remaining sessions get a `MemberLeave` right away, unless the whole server is shutting
+/// down. The server knows members only by session, so its user ID field holds the session ID.
+fn leave(registry: &parking_lot::Mutex<Registry>, id: SessionId, reason: DisconnectReason) {
+    remove_session(registry, &id);
+    if reason != DisconnectReason::ServerShutdown {
+        broadcast(registry, Packet::member_leave(id.0, Utc::now().timestamp_millis()));
+    }
+    if let Some(disconnects) = &registry.lock().disconnects {
+        let _ = disconnects.try_send((id, reason));
+    }
+}
+
+fn remove_session(registry: &parking_lot::Mutex<Registry>, id: &SessionId) {
+    let mut registry = registry.lock();
+    registry.users.retain(|user| user != id);
+    registry.tokens.retain(|_, session| session != id);
+    registry.senders.remove(id);
+}
+
+/// Give `token` a new session reached through `sender`, or `None` if it already has one.
+fn login(
+    registry: &parking_lot::Mutex<Registry>,
+    token: UserToken,
+    sender: &mpsc::Sender<Packet>,
+) -> Option<SessionId> {
+    let mut registry = registry.lock();
+    if registry.tokens.contains_key(&token) {
+        return None;
+    }
+    let id = SessionId::generate(&mut SNOWFLAKE.lock());
+    registry.tokens.insert(token, id);
+    registry.users.push(id);
+    registry.senders.insert(id, sender.clone());
+    Some(id)
+}
+
+/// Queue `packet` for the connection's writer, failing if the writer has stopped or the peer has
+/// let its write queue fill up, which disconnects it.
+fn reply(sender: &mpsc::Sender<Packet>, packet: Packet) -> Result<()> {
+    sender.try_send(packet).map_err(|e| match e {
+        TrySendError::Full(_) => std::io::Error::other("the connection's write queue is full"),
+        TrySendError::Closed(_) => std::io::Error::from(std::io::ErrorKind::BrokenPipe),
+    })
+}
+
+/// Read the next packet, failing with `TimedOut` if `ping_timeout` passes without one.
+async fn read_within<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    ping_timeout: Option<Duration>,
+) -> Result<Option<Packet>> {
+    match ping_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, wire::read_packet(socket)).await {
+            Ok(packet) => packet,
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        },
+        None => wire::read_packet(socket).await,
+    }
+}
+
+/// Read and dispatch packets from one connection until it closes, returning how it closed cleanly,
+/// or failing with `TimedOut` or `InvalidData` for a silent or misbehaving peer. `session` is set
+/// once the connection logs in, so the caller can remove it afterwards.
+async fn handle_connection<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    sender: mpsc::Sender<Packet>,
+    registry: &parking_lot::Mutex<Registry>,
+    session: &mut Option<SessionId>,
+    limits: Limits,
+) -> Result<DisconnectReason> {
+    let mut bucket = limits.rate_limit.map(|(max, per)| TokenBucket::new(max, per));
+    while let Some(packet) = read_within(socket, limits.ping_timeout).await? {
+        if let Err(e) = packet.validate() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+        if let Some(bucket) = &mut bucket {
+            if packet.op() != Opcode::Health && !bucket.try_take() {
+                reply(&sender, Packet::error(ErrorCode::RateLimited))?;
+                continue;
+            }
+        }
+        match packet.try_op() {
+            Ok(Opcode::Ping | Opcode::Health) => reply(&sender, Packet::ping_reply(&packet))?,
+            Ok(Opcode::Login) => {
+                let id = match session {
+                    Some(_) => None,
+                    None => login(registry, packet.login_token().unwrap(), &sender),
+                };
+                let answer = match id {
+                    Some(id) => {
+                        *session = Some(id);
+                        Packet::login_ok(id)
+                    }
+                    None => Packet::error(ErrorCode::AlreadyLoggedIn),
+                };
+                reply(&sender, answer)?
+            }
+            Ok(Opcode::Goodbye) => return Ok(DisconnectReason::ClientClosed),
+            // Anything else from a logged in session is left to the `incoming` stream, if any.
+            _ => {
+                if let Some(id) = *session {
+                    let inbound = registry.lock().inbound.clone();
+                    if let Some(inbound) = inbound {
+                        let _ = inbound.send((id, packet)).await;
+                    }
+                }
+            }
+        }
+    }
+    Ok(DisconnectReason::ConnectionLost)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::base_types::{packet::Severity, stream::PacketStream};
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+    use tokio_stream::StreamExt;
+
+    /// Start running `server` in the background, returning it with the address it listens on.
+    async fn spawn_server(server: Server) -> (Arc<Server>, SocketAddr) {
+        let server = Arc::new(server);
+        let addr = server.local_addr().await.unwrap();
+        tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+        (server, addr)
+    }
+
+    #[tokio::test]
+    async fn sessions_stream() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        server.register_sessions([SessionId(1), SessionId(2), SessionId(3)]);
+
+        let stream = server.sessions_stream();
+        server.registry.lock().users.clear();
+
+        let sessions: Vec<SessionInfo> = stream.collect().await;
+        assert_eq!(
+            sessions,
+            vec![
+                SessionInfo { id: SessionId(1) },
+                SessionInfo { id: SessionId(2) },
+                SessionInfo { id: SessionId(3) },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn from_std() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::from_std(listener).unwrap();
+
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (_, peer) = server.listener.lock().await.as_ref().unwrap().accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn run() {
+        let (_, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+
+        // A client that hangs up mid-packet must not take the server down.
+        let mut truncated = TcpStream::connect(addr).await.unwrap();
+        truncated.write_all(&[1; 100]).await.unwrap();
+        drop(truncated);
+
+        let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        client.write_packet(&Packet::ping_with_nonce(1, 42)).await.unwrap();
+        let reply = client.read_packet().await.unwrap();
+        assert_eq!(reply.op(), Opcode::Ok);
+        assert_eq!(reply.ping_nonce(), Some(42));
+    }
+
+    #[tokio::test]
+    async fn local_addr_while_running() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+        tokio::task::yield_now().await;
+
+        let during = tokio::time::timeout(Duration::from_secs(1), server.local_addr()).await;
+        assert_eq!(during.unwrap().unwrap(), addr);
+        server.shutdown().await;
+        assert!(server.local_addr().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        let mut first = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        first.write_packet(&Packet::login(token)).await.unwrap();
+        let session = first.read_packet().await.unwrap().login_ok_session().unwrap();
+
+        let mut second = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        second.write_packet(&Packet::login(token)).await.unwrap();
+        let reply = second.read_packet().await.unwrap();
+        assert_eq!(reply.error_code(), Some(ErrorCode::AlreadyLoggedIn));
+
+        let sessions: Vec<SessionInfo> = server.sessions_stream().collect().await;
+        assert_eq!(sessions, vec![SessionInfo { id: session }]);
+
+        // A different token gets its own session.
+        let mut third = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        let token = UserToken::from_bytes(*b"fedcba9876543210");
+        third.write_packet(&Packet::login(token)).await.unwrap();
+        let other = third.read_packet().await.unwrap().login_ok_session().unwrap();
+        assert_ne!(other, session);
+        drop(third);
+        while server.session_count() != 1 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        // Disconnecting frees the session and the token.
+        drop(first);
+        while server.session_count() != 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        second.write_packet(&Packet::login(token)).await.unwrap();
+        assert!(second.read_packet().await.unwrap().login_ok_session().is_some());
+    }
+
+    #[tokio::test]
+    async fn login_twice() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+
+        let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        client
+            .write_packet(&Packet::login(UserToken::from_bytes(*b"0123456789abcdef")))
+            .await
+            .unwrap();
+        let session = client.read_packet().await.unwrap().login_ok_session().unwrap();
+
+        // A logged in connection can't log in again, not even with another token.
+        client
+            .write_packet(&Packet::login(UserToken::from_bytes(*b"fedcba9876543210")))
+            .await
+            .unwrap();
+        let reply = client.read_packet().await.unwrap();
+        assert_eq!(reply.error_code(), Some(ErrorCode::AlreadyLoggedIn));
+
+        let sessions: Vec<SessionInfo> = server.sessions_stream().collect().await;
+        assert_eq!(sessions, vec![SessionInfo { id: session }]);
+
+        // The rejected token wasn't claimed, so another connection can still use it.
+        let mut other = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        other
+            .write_packet(&Packet::login(UserToken::from_bytes(*b"fedcba9876543210")))
+            .await
+            .unwrap();
+        assert!(other.read_packet().await.unwrap().login_ok_session().is_some());
+    }
+
+    #[tokio::test]
+    async fn goodbye() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+        let mut disconnects = server.disconnects();
+
+        let mut clients = Vec::new();
+        for token in [*b"0123456789abcdef", *b"fedcba9876543210"] {
+            let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+            client.write_packet(&Packet::login(UserToken::from_bytes(token))).await.unwrap();
+            let session = client.read_packet().await.unwrap().login_ok_session().unwrap();
+            clients.push((client, session));
+        }
+        assert_eq!(server.session_count(), 2);
+
+        // Saying goodbye ends the session and the server closes its side of the connection.
+        let (mut leaving, session) = clients.remove(0);
+        let mut goodbye = Packet::new([0; 1024]);
+        goodbye.reset(Opcode::Goodbye);
+        leaving.write_packet(&goodbye).await.unwrap();
+        assert!(leaving.read_packet().await.is_err());
+        assert_eq!(disconnects.next().await, Some((session, DisconnectReason::ClientClosed)));
+        assert_eq!(server.session_count(), 1);
+
+        // Everyone else hears about it straight away.
+        let (mut staying, _) = clients.remove(0);
+        let left = staying.read_packet().await.unwrap();
+        assert_eq!(left.op(), Opcode::MemberLeave);
+        assert_eq!(left.user_id(), Some(session.0));
+
+        // Hanging up without a goodbye is told apart.
+        drop(staying);
+        let (_, reason) = disconnects.next().await.unwrap();
+        assert_eq!(reason, DisconnectReason::ConnectionLost);
+    }
+
+    #[tokio::test]
+    async fn ping_timeout() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let (server, addr) =
+            spawn_server(server.with_ping_timeout(Duration::from_millis(100))).await;
+        let mut disconnects = server.disconnects();
+
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        let mut pinging = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        pinging.write_packet(&Packet::login(token)).await.unwrap();
+        assert!(pinging.read_packet().await.unwrap().login_ok_session().is_some());
+
+        let mut silent = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        silent
+            .write_packet(&Packet::login(UserToken::from_bytes(*b"fedcba9876543210")))
+            .await
+            .unwrap();
+        let reaped = silent.read_packet().await.unwrap().login_ok_session().unwrap();
+        assert_eq!(server.session_count(), 2);
+
+        // Pinging well within the timeout, for longer than the timeout, keeps the connection. The
+        // silent client leaving meanwhile is announced.
+        let mut left = None;
+        for nonce in 0..6 {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            pinging.write_packet(&Packet::ping_with_nonce(1, nonce)).await.unwrap();
+            let mut reply = pinging.read_packet().await.unwrap();
+            if reply.op() == Opcode::MemberLeave {
+                left = reply.user_id();
+                reply = pinging.read_packet().await.unwrap();
+            }
+            assert_eq!(reply.ping_nonce(), Some(nonce));
+        }
+        assert_eq!(left, Some(reaped.0));
+
+        // The silent client was reaped, so its next read sees the connection closed.
+        assert!(silent.read_packet().await.is_err());
+        assert_eq!(disconnects.next().await, Some((reaped, DisconnectReason::TimedOut)));
+        assert_eq!(server.session_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn broadcast() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+
+        let mut clients = Vec::new();
+        for token in [*b"0123456789abcdef", *b"fedcba9876543210"] {
+            let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+            client.write_packet(&Packet::login(UserToken::from_bytes(token))).await.unwrap();
+            assert!(client.read_packet().await.unwrap().login_ok_session().is_some());
+            clients.push(client);
+        }
+
+        let message = Packet::message(1, 2, SessionId(3), "hello everyone").unwrap();
+        server.broadcast(message);
+        for client in &mut clients {
+            let received = client.read_packet().await.unwrap();
+            assert_eq!(received.message_content().unwrap(), "hello everyone");
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_to_a_full_queue() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let (tx, mut rx) = mpsc::channel(OUTBOUND_CAPACITY);
+        server.registry.lock().senders.insert(SessionId(1), tx);
+
+        // A connection that doesn't keep up misses packets instead of queueing them without end.
+        for _ in 0..OUTBOUND_CAPACITY + 8 {
+            server.broadcast(Packet::message(1, 2, SessionId(3), "hello").unwrap());
+        }
+        let mut queued = 0;
+        while rx.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, OUTBOUND_CAPACITY);
+        assert!(server.registry.lock().senders.contains_key(&SessionId(1)));
+
+        // Once it's gone it is forgotten.
+        drop(rx);
+        server.broadcast(Packet::message(1, 2, SessionId(3), "hello").unwrap());
+        assert!(server.registry.lock().senders.is_empty());
+    }
+
+    #[tokio::test]
+    async fn incoming() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+        let incoming = server.incoming();
+
+        let mut clients = Vec::new();
+        for token in [*b"0123456789abcdef", *b"fedcba9876543210"] {
+            let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+            client.write_packet(&Packet::login(UserToken::from_bytes(token))).await.unwrap();
+            let session = client.read_packet().await.unwrap().login_ok_session().unwrap();
+            clients.push((client, session));
+        }
+
+        // A malformed frame drops its own connection, but not the stream.
+        let (mut bad, _) = clients.remove(0);
+        let mut malformed = Packet::message(1, 2, SessionId(3), "bad").unwrap();
+        malformed.0[1] = 200;
+        bad.write_packet(&malformed).await.unwrap();
+        assert!(bad.read_packet().await.is_err());
+
+        let (mut good, session) = clients.remove(0);
+        for text in ["one", "two", "three"] {
+            good.write_packet(&Packet::message(1, 2, session, text).unwrap()).await.unwrap();
+        }
+
+        let received: Vec<(SessionId, Packet)> = incoming.take(3).collect().await;
+        let texts: Vec<String> =
+            received.iter().map(|(_, p)| p.message_content().unwrap()).collect();
+        assert_eq!(texts, ["one", "two", "three"]);
+        assert!(received.iter().all(|(id, _)| *id == session));
+    }
+
+    #[tokio::test]
+    async fn incoming_backpressure() {
+        let (server, addr) = spawn_server(Server::new("127.0.0.1:0").await.unwrap()).await;
+        let mut incoming = server.incoming();
+
+        let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        client.write_packet(&Packet::login(token)).await.unwrap();
+        let session = client.read_packet().await.unwrap().login_ok_session().unwrap();
+
+        // With the stream full the connection stops reading, so a ping behind it goes unanswered.
+        for n in 0..INBOUND_CAPACITY + 1 {
+            let text = n.to_string();
+            client.write_packet(&Packet::message(1, 2, session, &text).unwrap()).await.unwrap();
+        }
+        client.write_packet(&Packet::ping_with_nonce(1, 7)).await.unwrap();
+        let stalled = tokio::time::timeout(Duration::from_millis(100), client.read_packet()).await;
+        assert!(stalled.is_err());
+
+        // Nothing was dropped, and taking packets lets the connection read again.
+        for n in 0..INBOUND_CAPACITY + 1 {
+            let (_, packet) = incoming.next().await.unwrap();
+            assert_eq!(packet.message_content().unwrap(), n.to_string());
+        }
+        assert_eq!(client.read_packet().await.unwrap().ping_nonce(), Some(7));
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let server = Arc::new(Server::new("127.0.0.1:0").await.unwrap());
+        let addr = server.local_addr().await.unwrap();
+        let running = tokio::spawn({
+            let server = server.clone();
+            async move { server.run().await }
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        wire::write_packet(&mut client, &Packet::login(token)).await.unwrap();
+        let login_ok = wire::read_packet(&mut client).await.unwrap().unwrap();
+        assert!(login_ok.login_ok_session().is_some());
+
+        // A packet queued before shutdown is still delivered, then the connection closes cleanly.
+        server.broadcast(Packet::notice(Severity::Info, "restarting").unwrap());
+        server.shutdown().await;
+        let notice = wire::read_packet(&mut client).await.unwrap().unwrap();
+        assert_eq!(notice.notice_text().unwrap(), "restarting");
+        assert!(wire::read_packet(&mut client).await.unwrap().is_none());
+
+        running.await.unwrap().unwrap();
+        assert_eq!(server.session_count(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stalled_peer() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let (server, addr) =
+            spawn_server(server.with_shutdown_grace(Duration::from_millis(100))).await;
+
+        // The peer logs in and then never reads again.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let token = UserToken::from_bytes(*b"0123456789abcdef");
+        wire::write_packet(&mut client, &Packet::login(token)).await.unwrap();
+        let login_ok = wire::read_packet(&mut client).await.unwrap().unwrap();
+        assert!(login_ok.login_ok_session().is_some());
+
+        // Fill the socket buffers, so the connection's writer blocks and its queue fills up.
+        let notice = Packet::notice(Severity::Info, &"x".repeat(1000)).unwrap();
+        for _ in 0..20_000 {
+            server.broadcast(Packet::new(notice.0));
+            tokio::task::yield_now().await;
+        }
+
+        let shutdown = tokio::time::timeout(Duration::from_secs(2), server.shutdown()).await;
+        assert!(shutdown.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let (_, addr) = spawn_server(server.with_rate_limit(3, Duration::from_secs(60))).await;
+
+        let mut client = PacketStream::new(TcpStream::connect(addr).await.unwrap());
+        for nonce in 0..5 {
+            client.write_packet(&Packet::ping_with_nonce(1, nonce)).await.unwrap();
+        }
+        for nonce in 0..3 {
+            assert_eq!(client.read_packet().await.unwrap().ping_nonce(), Some(nonce));
+        }
+        for _ in 3..5 {
+            let reply = client.read_packet().await.unwrap();
+            assert_eq!(reply.error_code(), Some(ErrorCode::RateLimited));
+        }
+
+        // Health probes get through even with the budget spent.
+        let mut health = Packet::new([0; 1024]);
+        health.reset(Opcode::Health);
+        client.write_packet(&health).await.unwrap();
+        assert_eq!(client.read_packet().await.unwrap().op(), Opcode::Ok);
+    }
+
+    #[tokio::test]
+    async fn add_and_remove_session() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        server.add_session(SessionId(1));
+        server.add_session(SessionId(1));
+        assert_eq!(server.session_count(), 1);
+
+        server.remove_session(&SessionId(1));
+        assert_eq!(server.session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn bulk_sessions() {
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+
+        server.register_sessions((0..1000).map(SessionId));
+        assert_eq!(server.session_count(), 1000);
+
+        // Registering a session twice has no effect, in bulk as well.
+        server.add_session(SessionId(1000));
+        server.register_sessions([SessionId(0), SessionId(1000), SessionId(1001), SessionId(1001)]);
+        assert_eq!(server.session_count(), 1002);
+        server.unregister_sessions([SessionId(1000), SessionId(1001)]);
+
+        server.unregister_sessions((0..500).map(SessionId));
+        assert_eq!(server.session_count(), 500);
     }
 }
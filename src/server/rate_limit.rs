@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+/// A token bucket holding up to `capacity` packets that refills continuously, so a burst is
+/// smoothed out instead of resetting at fixed window boundaries.
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    /// Tokens regained per second.
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Allow `max_packets` every `per`, starting with a full bucket.
+    pub(crate) fn new(max_packets: u32, per: Duration) -> Self {
+        TokenBucket {
+            capacity: max_packets as f64,
+            rate: max_packets as f64 / per.as_secs_f64(),
+            tokens: max_packets as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one.
+    pub(crate) fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn refills_continuously() {
+        let mut bucket = TokenBucket::new(4, Duration::from_secs(1));
+        let start = bucket.last;
+        assert!((0..4).all(|_| bucket.try_take_at(start)));
+        assert!(!bucket.try_take_at(start));
+
+        // A quarter of the period buys back exactly one packet, not a whole new window.
+        let later = start + Duration::from_millis(250);
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+
+        // An idle bucket never holds more than its capacity.
+        let much_later = later + Duration::from_secs(10);
+        assert!((0..4).all(|_| bucket.try_take_at(much_later)));
+        assert!(!bucket.try_take_at(much_later));
+    }
+}